@@ -0,0 +1,108 @@
+use anyhow::Error;
+
+// deepest nesting of lists and dicts we'll walk; the bytes come from peers, and each level is
+// a stack frame
+pub const MAX_DEPTH: usize = 64;
+
+// Returns the length in bytes of the first bencoded value at the start of `bytes`.
+pub fn value_len(bytes: &[u8]) -> Result<usize, Error> {
+    nested_value_len(bytes, MAX_DEPTH)
+}
+
+// Like `value_len`, but fails on lists and dicts nested more than `max_depth` deep.
+pub fn nested_value_len(bytes: &[u8], max_depth: usize) -> Result<usize, Error> {
+    match bytes.first() {
+        Some(b'i') => bytes
+            .iter()
+            .position(|&b| b == b'e')
+            .map(|pos| pos + 1)
+            .ok_or_else(|| anyhow::anyhow!("unterminated bencoded integer")),
+        Some(b'l') | Some(b'd') => {
+            if max_depth == 0 {
+                return Err(anyhow::anyhow!("bencoded value nested too deeply"));
+            }
+            let mut pos = 1;
+            loop {
+                match bytes.get(pos) {
+                    Some(b'e') => return Ok(pos + 1),
+                    Some(_) => pos += nested_value_len(&bytes[pos..], max_depth - 1)?,
+                    None => return Err(anyhow::anyhow!("unterminated bencoded list or dict")),
+                }
+            }
+        }
+        Some(b'0'..=b'9') => {
+            let colon = bytes
+                .iter()
+                .position(|&b| b == b':')
+                .ok_or_else(|| anyhow::anyhow!("missing ':' in bencoded string"))?;
+            let length: usize = std::str::from_utf8(&bytes[..colon])?
+                .parse()
+                .map_err(|e| anyhow::anyhow!("invalid bencoded string length: {}", e))?;
+            match (colon + 1).checked_add(length) {
+                Some(end) if end <= bytes.len() => Ok(end),
+                _ => Err(anyhow::anyhow!("truncated bencoded string")),
+            }
+        }
+        Some(b) => Err(anyhow::anyhow!("invalid bencode prefix: {:?}", *b as char)),
+        None => Err(anyhow::anyhow!("empty bencoded value")),
+    }
+}
+
+// Returns the raw bytes of the value under `key` in the bencoded dict at the start of `bytes`.
+pub fn dict_value<'a>(bytes: &'a [u8], key: &[u8]) -> Result<Option<&'a [u8]>, Error> {
+    if bytes.first() != Some(&b'd') {
+        return Err(anyhow::anyhow!("not a bencoded dict"));
+    }
+    let wanted = [key.len().to_string().as_bytes(), b":", key].concat();
+    let mut pos = 1;
+    while bytes.get(pos).is_some_and(|b| *b != b'e') {
+        let key_len = value_len(&bytes[pos..])?;
+        let value_start = pos + key_len;
+        let value_end = value_start + value_len(&bytes[value_start..])?;
+        if bytes[pos..value_start] == wanted[..] {
+            return Ok(Some(&bytes[value_start..value_end]));
+        }
+        pos = value_end;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_values() {
+        assert_eq!(value_len(b"i42eextra").unwrap(), 4);
+        assert_eq!(value_len(b"4:spamextra").unwrap(), 6);
+        assert_eq!(value_len(b"d1:ali1ei2eee1:x").unwrap(), 13);
+    }
+
+    #[test]
+    fn refuses_malformed_values() {
+        assert!(value_len(b"4:spa").is_err());
+        assert!(value_len(b"18446744073709551615:x").is_err());
+        assert!(value_len(b"li1e").is_err());
+        assert!(value_len(b"x").is_err());
+        assert!(value_len(b"").is_err());
+    }
+
+    #[test]
+    fn finds_dict_values() {
+        let dict = b"d8:announce3:url4:infod4:name1:xee";
+        assert_eq!(
+            dict_value(dict, b"info").unwrap(),
+            Some(&b"d4:name1:xe"[..])
+        );
+        assert_eq!(dict_value(dict, b"announce").unwrap(), Some(&b"3:url"[..]));
+        assert_eq!(dict_value(dict, b"name").unwrap(), None);
+        assert!(dict_value(b"li1ee", b"info").is_err());
+    }
+
+    #[test]
+    fn refuses_deep_nesting() {
+        let nested = |depth| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        assert_eq!(value_len(&nested(MAX_DEPTH)).unwrap(), MAX_DEPTH * 2);
+        assert!(value_len(&nested(MAX_DEPTH + 1)).is_err());
+    }
+}
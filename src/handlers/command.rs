@@ -1,14 +1,47 @@
-use std::{net::SocketAddr, path::PathBuf};
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+};
 
 use clap::{Parser, Subcommand};
 
 use super::{magnet_handler, torrent_handler};
+use crate::{
+    listener::{Listener, DEFAULT_MAX_CONNECTIONS, DEFAULT_PORT},
+    mse::EncryptionPolicy,
+    ratelimit::{Direction, RateLimits},
+    storage::{allocate::AllocationMode, StorageBackend},
+    tcp::{ConnectionOptions, TransportPreference},
+    torrent::{picker::PickStrategy, pipeline::DEFAULT_MAX_REQUESTS, swarm::DEFAULT_MAX_PEERS},
+    utp::UtpSockets,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
     #[command(subcommand)]
     command: Command,
+    #[arg(long, global = true, value_enum, default_value_t = EncryptionPolicy::Prefer)]
+    encryption: EncryptionPolicy,
+    #[arg(long, global = true, value_enum, default_value_t = TransportPreference::PreferTcp)]
+    transport: TransportPreference,
+    #[arg(long, global = true, default_value_t = DEFAULT_MAX_REQUESTS)]
+    max_requests: usize,
+    #[arg(long, global = true, default_value_t = DEFAULT_MAX_PEERS)]
+    max_peers: usize,
+    #[arg(long, global = true, value_enum, default_value_t = PickStrategy::RarestFirst)]
+    strategy: PickStrategy,
+    #[command(flatten)]
+    rate_limits: RateLimitOptions,
+    // address and port peers can connect to us on, while downloading or serving
+    #[arg(long, global = true, default_value = "0.0.0.0")]
+    listen: IpAddr,
+    #[arg(long, global = true, default_value_t = DEFAULT_PORT)]
+    port: u16,
+    // incoming peer connections open at once
+    #[arg(long, global = true, default_value_t = DEFAULT_MAX_CONNECTIONS)]
+    max_connections: usize,
 }
 
 #[derive(Subcommand, Debug)]
@@ -33,10 +66,40 @@ pub enum Command {
         torrent: PathBuf,
         piece_index: u32,
     },
+    // Fetches just the bytes `offset..offset + length` of the torrent's content, or of one
+    // file in it, verifying the pieces that cover them.
+    #[command(name = "download_range")]
+    DownloadRange {
+        #[arg(short = 'o')]
+        save_path: PathBuf,
+        torrent: PathBuf,
+        // index or path of a file in the torrent; offsets are then relative to that file
+        #[arg(long)]
+        file: Option<String>,
+        #[arg(long, default_value_t = 0)]
+        offset: u64,
+        // defaults to the rest of the torrent or file
+        #[arg(long)]
+        length: Option<u64>,
+    },
     Download {
         #[arg(short = 'o')]
         save_path: PathBuf,
         torrent: PathBuf,
+        #[command(flatten)]
+        download: DownloadOptions,
+    },
+    // Serves the torrent's files over HTTP, downloading to `save_path` on demand.
+    Serve {
+        #[arg(short = 'o')]
+        save_path: PathBuf,
+        torrent: PathBuf,
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        bind: SocketAddr,
+    },
+    Verify {
+        torrent: PathBuf,
+        path: PathBuf,
     },
     #[command(name = "magnet_parse")]
     MagnetParse {
@@ -62,43 +125,205 @@ pub enum Command {
         #[arg(short = 'o')]
         save_path: PathBuf,
         link: String,
+        #[command(flatten)]
+        download: DownloadOptions,
     },
 }
 
+// Settings for full downloads to disk.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct DownloadOptions {
+    // hash-check the existing output and re-fetch only the pieces that fail
+    #[arg(long)]
+    pub repair: bool,
+    #[arg(long, value_enum, default_value_t = AllocationMode::Sparse)]
+    pub allocation: AllocationMode,
+    #[arg(long, value_enum, default_value_t = StorageBackend::File)]
+    pub storage: StorageBackend,
+    // only download files matching these indexes or globs
+    #[arg(long)]
+    pub select: Vec<String>,
+    // SELECTOR=skip|low|normal|high, e.g. `--priority '*.nfo=skip'`
+    #[arg(long)]
+    pub priority: Vec<String>,
+}
+
+// Bandwidth limits in KiB/s; 0 is unlimited.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct RateLimitOptions {
+    #[arg(long, global = true, default_value_t = 0, value_name = "KIB/S")]
+    upload_limit: u64,
+    #[arg(long, global = true, default_value_t = 0, value_name = "KIB/S")]
+    download_limit: u64,
+    #[arg(long, global = true, default_value_t = 0, value_name = "KIB/S")]
+    torrent_upload_limit: u64,
+    #[arg(long, global = true, default_value_t = 0, value_name = "KIB/S")]
+    torrent_download_limit: u64,
+    #[arg(long, global = true, default_value_t = 0, value_name = "KIB/S")]
+    peer_upload_limit: u64,
+    #[arg(long, global = true, default_value_t = 0, value_name = "KIB/S")]
+    peer_download_limit: u64,
+    // don't limit peers on loopback, private or link-local addresses
+    #[arg(long, global = true)]
+    exempt_lan: bool,
+}
+
+impl RateLimitOptions {
+    fn build(&self) -> RateLimits {
+        let limits = RateLimits::default();
+        let bandwidths = [
+            (limits.global(), self.upload_limit, self.download_limit),
+            (
+                limits.torrent(),
+                self.torrent_upload_limit,
+                self.torrent_download_limit,
+            ),
+            (
+                limits.peer(),
+                self.peer_upload_limit,
+                self.peer_download_limit,
+            ),
+        ];
+        for (bandwidth, upload, download) in bandwidths {
+            bandwidth.set_limit(Direction::Upload, upload * 1024);
+            bandwidth.set_limit(Direction::Download, download * 1024);
+        }
+        limits.set_exempt_lan(self.exempt_lan);
+        limits
+    }
+}
+
 impl Args {
+    async fn connection_options(&self) -> ConnectionOptions {
+        ConnectionOptions {
+            encryption: self.encryption,
+            transport: self.transport,
+            utp_sockets: UtpSockets::default(),
+            max_requests: self.max_requests,
+            max_peers: self.max_peers,
+            strategy: self.strategy,
+            rate_limits: self.rate_limits.build(),
+            listener: self.listener().await,
+        }
+    }
+
+    // Only the long-running commands accept peers. Without a listener we can still download,
+    // so failing to bind is just a warning.
+    async fn listener(&self) -> Option<Arc<Listener>> {
+        if !matches!(
+            self.command,
+            Command::Download { .. } | Command::Serve { .. } | Command::MagnetDownload { .. }
+        ) {
+            return None;
+        }
+        let addr = SocketAddr::new(self.listen, self.port);
+        match Listener::bind(addr, self.encryption, self.max_connections).await {
+            Ok(listener) => {
+                let listener = Arc::new(listener);
+                tokio::spawn(listener.clone().run());
+                Some(listener)
+            }
+            Err(e) => {
+                eprintln!("Not accepting incoming peers: {}", e);
+                None
+            }
+        }
+    }
+
     pub async fn handle(&self) {
+        let options = self.connection_options().await;
         match &self.command {
             Command::Decode { encoded_value } => {
                 torrent_handler::decode_bencoded_value(encoded_value.as_str())
             }
-            Command::Info { torrent } => torrent_handler::get_info(&torrent),
-            Command::Peers { torrent } => torrent_handler::peers(&torrent).await,
+            Command::Info { torrent } => torrent_handler::get_info(torrent),
+            Command::Peers { torrent } => torrent_handler::peers(torrent).await,
             Command::Handshake { torrent, peer } => {
-                torrent_handler::handshake_handler(torrent.clone(), peer.clone()).await
+                torrent_handler::handshake_handler(torrent.clone(), *peer, options).await
             }
             Command::DownloadPiece {
                 save_path,
                 torrent,
                 piece_index,
             } => {
-                torrent_handler::download_piece(save_path.clone(), torrent.clone(), *piece_index)
-                    .await
+                torrent_handler::download_piece(
+                    save_path.clone(),
+                    torrent.clone(),
+                    *piece_index,
+                    options,
+                )
+                .await
             }
-            Command::Download { save_path, torrent } => {
-                torrent_handler::downlaod(save_path.clone(), torrent.clone()).await
+            Command::DownloadRange {
+                save_path,
+                torrent,
+                file,
+                offset,
+                length,
+            } => {
+                torrent_handler::download_range(
+                    save_path.clone(),
+                    torrent.clone(),
+                    file.clone(),
+                    *offset,
+                    *length,
+                    options,
+                )
+                .await
+            }
+            Command::Download {
+                save_path,
+                torrent,
+                download,
+            } => {
+                torrent_handler::downlaod(
+                    save_path.clone(),
+                    torrent.clone(),
+                    download.clone(),
+                    options,
+                )
+                .await
+            }
+            Command::Serve {
+                save_path,
+                torrent,
+                bind,
+            } => torrent_handler::serve(save_path.clone(), torrent.clone(), *bind, options).await,
+            Command::Verify { torrent, path } => {
+                torrent_handler::verify(torrent.clone(), path.clone())
             }
             Command::MagnetParse { link } => magnet_handler::parse(link.clone()),
-            Command::MagnetHandshake { link } => magnet_handler::handshake(link.clone()).await,
-            Command::MagnetInfo { link } => magnet_handler::fetch_metadata_info(link.clone()).await,
+            Command::MagnetHandshake { link } => {
+                magnet_handler::handshake(link.clone(), options).await
+            }
+            Command::MagnetInfo { link } => {
+                magnet_handler::fetch_metadata_info(link.clone(), options).await
+            }
             Command::MagnetDownloadPiece {
                 save_path,
                 link,
                 piece_index,
             } => {
-                magnet_handler::download_piece(link.clone(), save_path.clone(), *piece_index).await
+                magnet_handler::download_piece(
+                    link.clone(),
+                    save_path.clone(),
+                    *piece_index,
+                    options,
+                )
+                .await
             }
-            Command::MagnetDownload { save_path, link } => {
-                magnet_handler::download_file(link.clone(), save_path.clone()).await
+            Command::MagnetDownload {
+                save_path,
+                link,
+                download,
+            } => {
+                magnet_handler::download_file(
+                    link.clone(),
+                    save_path.clone(),
+                    download.clone(),
+                    options,
+                )
+                .await
             }
         }
     }
@@ -1,8 +1,16 @@
-use std::{fs::File, io::Write, path::PathBuf};
+use std::{
+    fs::File,
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 
+use super::{command::DownloadOptions, progress, torrent_handler};
 use crate::{
     magnet::{client::MagnetClient, magnet::MagnetLink},
-    torrent::{client::Client, torrent::Torrent},
+    storage::{memory::MemoryStorage, Storage},
+    tcp::ConnectionOptions,
+    torrent::{events::Events, swarm::Swarm, torrent::Torrent},
 };
 
 pub fn parse(magnet_link: String) {
@@ -13,21 +21,30 @@ pub fn parse(magnet_link: String) {
     println!("Tracker URL: {}", magnet_link.tracker_url.unwrap());
 }
 
-pub async fn handshake(magnet_link: String) {
+// Connects to the first of the magnet's peers that answers, printing the ones that fail.
+async fn connect(magnet: &MagnetLink, options: ConnectionOptions) -> MagnetClient {
+    let mut events = Events::default();
+    tokio::spawn(progress::show(events.subscribe(), 0));
+    MagnetClient::new(magnet.clone(), options, events)
+        .await
+        .unwrap()
+}
+
+pub async fn handshake(magnet_link: String, options: ConnectionOptions) {
     let magnet = MagnetLink::from(magnet_link)
         .map_err(|e| e.to_string())
         .unwrap();
-    let mut client = MagnetClient::new(magnet.clone()).await;
+    let mut client = connect(&magnet, options).await;
     let (peer_id, extension_id) = client.extension_handshake().await.unwrap();
     println!("Peer ID: {}", peer_id);
     println!("Peer Metadata Extension ID: {}", extension_id);
 }
 
-pub async fn fetch_metadata_info(magnet_link: String) {
+pub async fn fetch_metadata_info(magnet_link: String, options: ConnectionOptions) {
     let magnet = MagnetLink::from(magnet_link)
         .map_err(|e| e.to_string())
         .unwrap();
-    let mut client = MagnetClient::new(magnet.clone()).await;
+    let mut client = connect(&magnet, options).await;
     let (_peer_id, extension_id) = client.extension_handshake().await.unwrap();
 
     let info = client.fetch_metadata_info(extension_id).await.unwrap();
@@ -35,45 +52,47 @@ pub async fn fetch_metadata_info(magnet_link: String) {
     torrent.pretty_print();
 }
 
-pub async fn download_piece(magnet_link: String, save_path: PathBuf, piece_index: u32) {
+pub async fn download_piece(
+    magnet_link: String,
+    save_path: PathBuf,
+    piece_index: u32,
+    options: ConnectionOptions,
+) {
     let magnet = MagnetLink::from(magnet_link)
         .map_err(|e| e.to_string())
         .unwrap();
-    let mut client = MagnetClient::new(magnet.clone()).await;
+    let mut client = connect(&magnet, options.clone()).await;
     let (_peer_id, extension_id) = client.extension_handshake().await.unwrap();
 
     let info = client.fetch_metadata_info(extension_id).await.unwrap();
     let torrent = Torrent::new(magnet.tracker_url.as_ref().unwrap().to_string(), info);
-    let mut client = Client::new(torrent);
-    let peer = magnet.fetch_peers().await.unwrap()[0];
-
-    client.handshake(peer).await.unwrap();
-    client.init_download().await.unwrap();
-    let data = client.download_piece(piece_index).await.unwrap();
+    let peers = magnet.fetch_peers().await.unwrap();
+    let piece_length = torrent.info.piece_length as u64;
+    let storage = Arc::new(Mutex::new(MemoryStorage::new(&torrent)));
+    let mut swarm = Swarm::new(torrent, peers, options, storage.clone());
+    let progress = tokio::spawn(progress::show(swarm.subscribe(), piece_length));
+    swarm.download_pieces(vec![piece_index]).await.unwrap();
+    drop(swarm);
+    progress.await.unwrap();
+    let piece = storage.lock().unwrap().read_piece(piece_index).unwrap();
     let mut file = File::create(save_path).unwrap();
-    file.write_all(&data).unwrap();
+    file.write_all(&piece).unwrap();
     file.flush().unwrap();
 }
 
-pub async fn download_file(magnet_link: String, save_path: PathBuf) {
+pub async fn download_file(
+    magnet_link: String,
+    save_path: PathBuf,
+    download: DownloadOptions,
+    options: ConnectionOptions,
+) {
     let magnet = MagnetLink::from(magnet_link)
         .map_err(|e| e.to_string())
         .unwrap();
-    let mut client = MagnetClient::new(magnet.clone()).await;
+    let mut client = connect(&magnet, options.clone()).await;
     let (_peer_id, extension_id) = client.extension_handshake().await.unwrap();
 
     let info = client.fetch_metadata_info(extension_id).await.unwrap();
     let torrent = Torrent::new(magnet.tracker_url.as_ref().unwrap().to_string(), info);
-    let mut client = Client::new(torrent.clone());
-    let peer = magnet.fetch_peers().await.unwrap()[0];
-
-    client.handshake(peer).await.unwrap();
-    client.init_download().await.unwrap();
-    let pieces = torrent.get_piece_count();
-    let mut file = File::create(save_path).unwrap();
-    for i in 0..pieces {
-        let data = client.download_piece(i as u32).await.unwrap();
-        file.write_all(&data).unwrap();
-    }
-    file.flush().unwrap();
+    torrent_handler::download_to_disk(torrent, save_path, download, options).await
 }
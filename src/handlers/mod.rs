@@ -1,3 +1,4 @@
 pub mod command;
 pub mod magnet_handler;
+pub mod progress;
 pub mod torrent_handler;
@@ -0,0 +1,164 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    io::{IsTerminal, Write},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::torrent::events::Event;
+
+const BAR_WIDTH: usize = 30;
+// transfer samples the speed is averaged over, about one a second
+const SPEED_SAMPLES: usize = 5;
+
+// Draws a progress bar with speed and ETA on stderr until the event channel closes. Problems
+// (failed and banned peers, hash failures, announce errors) and what was found on disk are
+// printed above the bar. When stderr isn't a terminal only those are printed.
+pub async fn show(mut events: UnboundedReceiver<Event>, piece_length: u64) {
+    let mut progress = Progress {
+        piece_length,
+        draw: std::io::stderr().is_terminal(),
+        ..Default::default()
+    };
+    while let Some(event) = events.recv().await {
+        progress.update(event);
+    }
+    if progress.draw && progress.total > 0 {
+        eprintln!();
+    }
+}
+
+#[derive(Default)]
+struct Progress {
+    piece_length: u64,
+    draw: bool,
+    completed: usize,
+    total: usize,
+    peers: HashSet<std::net::SocketAddr>,
+    // (when, bytes downloaded by then)
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl Progress {
+    fn update(&mut self, event: Event) {
+        match event {
+            Event::Announce {
+                result: Err(e),
+                tracker,
+            } => self.message(&format!("Announce to {} failed: {}", tracker, e)),
+            Event::Announce { .. } => {}
+            Event::Resumed { completed, total } => {
+                self.message(&format!("Resuming with {}/{} pieces", completed, total))
+            }
+            Event::Checked { good, total } => {
+                self.message(&format!("Found {}/{} good pieces on disk", good, total))
+            }
+            Event::Started { completed, total }
+            | Event::PieceCompleted {
+                completed, total, ..
+            } => {
+                self.completed = completed;
+                self.total = total;
+            }
+            Event::PeerConnected(peer) => {
+                self.peers.insert(peer);
+            }
+            Event::PeerDisconnected { peer, error } => {
+                self.peers.remove(&peer);
+                if let Some(e) = error {
+                    self.message(&format!("Peer {} failed: {}", peer, e));
+                }
+            }
+            Event::HashFailed { piece, peer } => self.message(&format!(
+                "Piece {} from {} failed its hash check",
+                piece, peer
+            )),
+            Event::PeerBanned(ip) => {
+                self.message(&format!("Banned {} for sending corrupt data", ip))
+            }
+            Event::Transferred { downloaded, .. } => {
+                self.samples.push_back((Instant::now(), downloaded));
+                if self.samples.len() > SPEED_SAMPLES {
+                    self.samples.pop_front();
+                }
+            }
+        }
+        self.redraw();
+    }
+
+    // bytes per second over the last few samples
+    fn speed(&self) -> f64 {
+        let (Some((start, from)), Some((end, to))) = (self.samples.front(), self.samples.back())
+        else {
+            return 0.0;
+        };
+        let elapsed = end.duration_since(*start).as_secs_f64();
+        if elapsed == 0.0 {
+            return 0.0;
+        }
+        (to - from) as f64 / elapsed
+    }
+
+    fn message(&self, message: &str) {
+        if self.draw {
+            // clear the bar; it's drawn again below the message
+            eprint!("\r\x1b[2K");
+        }
+        eprintln!("{}", message);
+    }
+
+    fn redraw(&self) {
+        if !self.draw || self.total == 0 {
+            return;
+        }
+        let fraction = self.completed as f64 / self.total as f64;
+        let filled = (fraction * BAR_WIDTH as f64) as usize;
+        let speed = self.speed();
+        let left = (self.total - self.completed) as u64 * self.piece_length;
+        let eta = if self.completed == self.total {
+            "done".to_string()
+        } else if speed > 0.0 {
+            format_duration(Duration::from_secs_f64(left as f64 / speed))
+        } else {
+            "--:--".to_string()
+        };
+        eprint!(
+            "\r\x1b[2K[{}{}] {:5.1}% {}/{} pieces {} ETA {} {} peers",
+            "#".repeat(filled),
+            ".".repeat(BAR_WIDTH - filled),
+            fraction * 100.0,
+            self.completed,
+            self.total,
+            format_speed(speed),
+            eta,
+            self.peers.len()
+        );
+        let _ = std::io::stderr().flush();
+    }
+}
+
+fn format_speed(bytes_per_second: f64) -> String {
+    const UNITS: [&str; 4] = ["B/s", "KiB/s", "MiB/s", "GiB/s"];
+    let mut speed = bytes_per_second;
+    let mut unit = 0;
+    while speed >= 1024.0 && unit < UNITS.len() - 1 {
+        speed /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", speed, UNITS[unit])
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds >= 3600 {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    } else {
+        format!("{:02}:{:02}", seconds / 60, seconds % 60)
+    }
+}
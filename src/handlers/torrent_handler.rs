@@ -1,9 +1,31 @@
 use serde_json::Number;
-use std::{fs::File, io::Write, net::SocketAddr, path::PathBuf};
+use std::{
+    fs::File,
+    io::Write,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 
+use super::{command::DownloadOptions, progress};
 use crate::handshake::HandshakeMessage;
-use crate::tcp::TcpManager;
-use crate::torrent::{client::Client, torrent::Torrent};
+use crate::listener::DEFAULT_PORT;
+use crate::serve::{self, HttpServer};
+use crate::storage::{
+    file::FileStorage,
+    memory::MemoryStorage,
+    mmap::MmapStorage,
+    resume::ResumeFile,
+    select,
+    verify::{self, PieceStatus},
+    Storage, StorageBackend, StorageLayout,
+};
+use crate::tcp::{ConnectionOptions, TcpManager};
+use crate::torrent::{
+    picker::{PickStrategy, Priority},
+    swarm::Swarm,
+    torrent::Torrent,
+};
 
 fn jsonify(value: &serde_bencode::value::Value) -> serde_json::Value {
     match value {
@@ -23,7 +45,7 @@ fn jsonify(value: &serde_bencode::value::Value) -> serde_json::Value {
 }
 
 pub fn decode_bencoded_value(encoded_value: &str) {
-    let value = serde_bencode::from_str(&encoded_value).unwrap();
+    let value = serde_bencode::from_str(encoded_value).unwrap();
     println!("{}", jsonify(&value));
 }
 
@@ -34,7 +56,7 @@ pub fn get_info(file_name: &std::path::PathBuf) {
 
 pub async fn peers(file_name: &std::path::PathBuf) {
     let torrent = Torrent::from(file_name);
-    let peers = torrent.get_peers().await;
+    let peers = torrent.get_peers(DEFAULT_PORT).await;
     match peers {
         Ok(peers) => {
             for peer in peers {
@@ -47,10 +69,12 @@ pub async fn peers(file_name: &std::path::PathBuf) {
     }
 }
 
-pub async fn handshake_handler(torrent: PathBuf, peer: SocketAddr) {
+pub async fn handshake_handler(torrent: PathBuf, peer: SocketAddr, options: ConnectionOptions) {
     let torrent = Torrent::from(&torrent);
     let handshake_message = HandshakeMessage::new(torrent.get_info_hash(), false);
-    let mut stream = TcpManager::connect(peer).await;
+    let mut stream = TcpManager::connect_with(peer, torrent.get_info_hash(), &options)
+        .await
+        .unwrap();
     let handshake = stream
         .handshake(handshake_message)
         .await
@@ -58,29 +82,178 @@ pub async fn handshake_handler(torrent: PathBuf, peer: SocketAddr) {
     println!("Peer ID: {}", hex::encode(handshake.peer_id));
 }
 
-pub async fn download_piece(save_path: PathBuf, torrent: PathBuf, piece_index: u32) {
+pub async fn download_piece(
+    save_path: PathBuf,
+    torrent: PathBuf,
+    piece_index: u32,
+    options: ConnectionOptions,
+) {
     let torrent = Torrent::from(&torrent);
-    let peer = torrent.get_peers().await.unwrap()[0];
-    let mut client = Client::new(torrent);
-    client.handshake(peer).await.unwrap();
-    client.init_download().await.unwrap();
-    let data = client.download_piece(piece_index).await.unwrap();
+    let peers = torrent.get_peers(options.port()).await.unwrap();
+    let piece_length = torrent.info.piece_length as u64;
+    let storage = Arc::new(Mutex::new(MemoryStorage::new(&torrent)));
+    let mut swarm = Swarm::new(torrent, peers, options, storage.clone());
+    let progress = tokio::spawn(progress::show(swarm.subscribe(), piece_length));
+    swarm.download_pieces(vec![piece_index]).await.unwrap();
+    drop(swarm);
+    progress.await.unwrap();
+    let piece = storage.lock().unwrap().read_piece(piece_index).unwrap();
     let mut file = File::create(save_path).unwrap();
-    file.write_all(&data).unwrap();
+    file.write_all(&piece).unwrap();
 }
 
-pub async fn downlaod(save_path: PathBuf, torrent: PathBuf) {
+pub async fn download_range(
+    save_path: PathBuf,
+    torrent: PathBuf,
+    file: Option<String>,
+    offset: u64,
+    length: Option<u64>,
+    options: ConnectionOptions,
+) {
     let torrent = Torrent::from(&torrent);
-    let peer = torrent.get_peers().await.unwrap()[0];
-    let mut client = Client::new(torrent.clone());
-    client.handshake(peer).await.unwrap();
-    client.init_download().await.unwrap();
-    let pieces = torrent.get_piece_count();
-    let mut data = Vec::new();
-    for i in 0..pieces {
-        let piece_data = client.download_piece(i as u32).await.unwrap();
-        data.extend_from_slice(&piece_data);
-    }
+    let layout = StorageLayout::new(&torrent);
+    let range = match file {
+        Some(file) => {
+            let index = select::find_file(&torrent.get_file_names(), &file).unwrap();
+            let end = length.map_or(layout.files[index].length, |length| offset + length);
+            layout.file_range(index, offset..end).unwrap()
+        }
+        None => offset..length.map_or(layout.total_length, |length| offset + length),
+    };
+    let peers = torrent.get_peers(options.port()).await.unwrap();
+    let piece_length = torrent.info.piece_length as u64;
+    let storage = Arc::new(Mutex::new(MemoryStorage::new(&torrent)));
+    let mut swarm = Swarm::new(torrent, peers, options, storage);
+    let progress = tokio::spawn(progress::show(swarm.subscribe(), piece_length));
+    let data = swarm.download_range(range).await.unwrap();
+    drop(swarm);
+    progress.await.unwrap();
     let mut file = File::create(save_path).unwrap();
     file.write_all(&data).unwrap();
 }
+
+pub async fn downlaod(
+    save_path: PathBuf,
+    torrent: PathBuf,
+    download: DownloadOptions,
+    options: ConnectionOptions,
+) {
+    let torrent = Torrent::from(&torrent);
+    download_to_disk(torrent, save_path, download, options).await
+}
+
+// Shared by torrent and magnet downloads once the torrent is known.
+pub async fn download_to_disk(
+    torrent: Torrent,
+    save_path: PathBuf,
+    download: DownloadOptions,
+    options: ConnectionOptions,
+) {
+    if download.repair && !save_path.exists() {
+        eprintln!("Nothing to repair at {}", save_path.display());
+        std::process::exit(1);
+    }
+    let priorities = select::file_priorities(
+        &torrent.get_file_names(),
+        &download.select,
+        &download.priority,
+    )
+    .unwrap();
+    let selected: Vec<bool> = priorities
+        .iter()
+        .map(|priority| *priority != Priority::Skip)
+        .collect();
+    let storage: Arc<Mutex<dyn Storage>> = match download.storage {
+        StorageBackend::File => {
+            let mut storage =
+                FileStorage::create_selected(save_path.clone(), &torrent, &selected).unwrap();
+            storage.set_allocation(download.allocation);
+            Arc::new(Mutex::new(storage))
+        }
+        StorageBackend::Mmap => {
+            if selected.contains(&false) {
+                eprintln!("Memory-mapped storage can't skip files");
+                std::process::exit(1);
+            }
+            let mut storage = MmapStorage::create(save_path.clone(), &torrent).unwrap();
+            storage.set_allocation(download.allocation);
+            Arc::new(Mutex::new(storage))
+        }
+    };
+    let resume = ResumeFile::new(&save_path, &torrent).unwrap();
+    let piece_length = torrent.info.piece_length as u64;
+    let mut swarm = Swarm::new(torrent, Vec::new(), options, storage);
+    swarm.set_file_priorities(&priorities);
+    swarm.set_resume(resume);
+    let progress = tokio::spawn(progress::show(swarm.subscribe(), piece_length));
+    {
+        let mut run = std::pin::pin!(async {
+            swarm.announce().await?;
+            if download.repair {
+                swarm.repair().await
+            } else {
+                swarm.download().await
+            }
+        });
+        tokio::select! {
+            result = &mut run => result.unwrap(),
+            _ = tokio::signal::ctrl_c() => {
+                // the download returns once its peers have stopped writing
+                swarm.stop();
+                let _ = run.await;
+                swarm.save_resume().unwrap();
+                eprintln!("Interrupted, progress saved");
+                std::process::exit(130);
+            }
+        }
+    }
+    // the bar finishes once the swarm and its peers are gone
+    drop(swarm);
+    progress.await.unwrap();
+}
+
+pub async fn serve(
+    save_path: PathBuf,
+    torrent: PathBuf,
+    bind: SocketAddr,
+    mut options: ConnectionOptions,
+) {
+    let torrent = Torrent::from(&torrent);
+    let peers = torrent.get_peers(options.port()).await.unwrap();
+    let names = torrent.get_file_names();
+    let storage = FileStorage::create(save_path, &torrent).unwrap();
+    // readers boost the pieces they're waiting on; the rest are fetched in order
+    options.strategy = PickStrategy::Sequential;
+    let piece_length = torrent.info.piece_length as u64;
+    let mut swarm = Swarm::new(torrent, peers, options, Arc::new(Mutex::new(storage)));
+    tokio::spawn(progress::show(swarm.subscribe(), piece_length));
+    serve::serve(HttpServer::new(swarm, names), bind)
+        .await
+        .unwrap();
+}
+
+pub fn verify(torrent: PathBuf, path: PathBuf) {
+    let torrent = Torrent::from(&torrent);
+    let report = verify::verify(&path, &torrent).unwrap();
+    for piece in report.failed_pieces() {
+        println!("Piece {}: {:?}", piece, report.pieces[piece as usize]);
+    }
+    for file in &report.files {
+        println!("{}: {:?}", file.path.display(), file.status());
+        for range in &file.corrupt {
+            println!("  corrupt bytes {}..{}", range.start, range.end);
+        }
+        for range in &file.missing {
+            println!("  missing bytes {}..{}", range.start, range.end);
+        }
+    }
+    let ok = report
+        .pieces
+        .iter()
+        .filter(|status| **status == PieceStatus::Ok)
+        .count();
+    println!("{}/{} pieces ok", ok, report.pieces.len());
+    if !report.is_ok() {
+        std::process::exit(1);
+    }
+}
@@ -8,10 +8,10 @@ pub struct HandshakeMessage {
 }
 
 impl HandshakeMessage {
-    pub fn new(info_hash: [u8; 20], is_magnet: bool) -> Self {
+    pub fn new(info_hash: [u8; 20], extensions: bool) -> Self {
         let peer_id = generate_peer_id();
         let mut reserved = [0u8; 8];
-        if is_magnet {
+        if extensions {
             // 20th bit from last is 1
             reserved[5] = 16;
         }
@@ -35,6 +35,10 @@ impl HandshakeMessage {
         }
     }
 
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & 16 != 0
+    }
+
     pub fn to_bytes(&self) -> [u8; 68] {
         let mut bytes = [0u8; 68];
         bytes[0] = self.length;
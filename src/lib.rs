@@ -1,6 +1,14 @@
+pub mod bencode;
 pub mod handlers;
 pub mod handshake;
+pub mod listener;
 pub mod magnet;
+pub mod mse;
 pub mod peer_messages;
+pub mod pex;
+pub mod ratelimit;
+pub mod serve;
+pub mod storage;
 pub mod tcp;
 pub mod torrent;
+pub mod utp;
@@ -0,0 +1,158 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Error;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, error::TrySendError, Receiver, Sender},
+        OwnedSemaphorePermit, Semaphore,
+    },
+    time::timeout,
+};
+
+use crate::handshake::HandshakeMessage;
+use crate::mse::EncryptionPolicy;
+use crate::tcp::TcpManager;
+
+// the port we listen on and tell trackers about
+pub const DEFAULT_PORT: u16 = 6881;
+// incoming connections open at once across every torrent, including ones still handshaking
+pub const DEFAULT_MAX_CONNECTIONS: usize = 200;
+
+// how long a peer gets to send its handshake (and finish any encrypted handshake before it)
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// handshaken peers waiting for their torrent's swarm to take them
+const INCOMING_QUEUE: usize = 8;
+// pause after a failed accept, which is usually running out of file descriptors
+const ACCEPT_RETRY: Duration = Duration::from_millis(500);
+
+// A peer that connected to us and sent its handshake, which we haven't answered yet.
+pub struct IncomingPeer {
+    pub peer: SocketAddr,
+    pub stream: TcpManager,
+    pub handshake: HandshakeMessage,
+    // counts the connection against the listener's limit for as long as it's held
+    pub permit: OwnedSemaphorePermit,
+}
+
+// Accepts peer connections on one port for any number of torrents, handing each peer to the
+// torrent its handshake names. Peers asking for a torrent we don't have are turned away, as
+// are peers beyond the connection limit.
+#[derive(Debug)]
+pub struct Listener {
+    listener: TcpListener,
+    local_addr: SocketAddr,
+    torrents: Mutex<HashMap<[u8; 20], Sender<IncomingPeer>>>,
+    encryption: EncryptionPolicy,
+    connections: Arc<Semaphore>,
+}
+
+impl Listener {
+    pub async fn bind(
+        addr: SocketAddr,
+        encryption: EncryptionPolicy,
+        max_connections: usize,
+    ) -> Result<Self, Error> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to listen on {}: {}", addr, e))?;
+        let local_addr = listener.local_addr()?;
+        Ok(Self {
+            listener,
+            local_addr,
+            torrents: Mutex::new(HashMap::new()),
+            encryption,
+            connections: Arc::new(Semaphore::new(max_connections)),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // Peers connecting for `info_hash` are sent on the returned channel until it's dropped.
+    pub fn add_torrent(&self, info_hash: [u8; 20]) -> Receiver<IncomingPeer> {
+        let (tx, rx) = mpsc::channel(INCOMING_QUEUE);
+        self.torrents.lock().unwrap().insert(info_hash, tx);
+        rx
+    }
+
+    // Accepts peers forever. A failed accept is reported and retried rather than ending it.
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            let (stream, peer) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("Failed to accept a peer on {}: {}", self.local_addr, e);
+                    tokio::time::sleep(ACCEPT_RETRY).await;
+                    continue;
+                }
+            };
+            // dropping the stream closes it; rejected peers aren't worth reporting
+            let Ok(permit) = self.connections.clone().try_acquire_owned() else {
+                continue;
+            };
+            let listener = self.clone();
+            tokio::spawn(async move {
+                let _ = listener.dispatch(stream, peer, permit).await;
+            });
+        }
+    }
+
+    async fn dispatch(
+        &self,
+        stream: TcpStream,
+        peer: SocketAddr,
+        permit: OwnedSemaphorePermit,
+    ) -> Result<(), Error> {
+        let info_hashes: Vec<[u8; 20]> = self.torrents.lock().unwrap().keys().copied().collect();
+        let (stream, handshake) = timeout(HANDSHAKE_TIMEOUT, async {
+            let (mut stream, encrypted_for) =
+                TcpManager::accept(stream, &info_hashes, self.encryption).await?;
+            let handshake = stream.read_handshake().await?;
+            if &handshake.protocol != b"BitTorrent protocol" {
+                return Err(anyhow::anyhow!("Not a BitTorrent handshake"));
+            }
+            if encrypted_for.is_some_and(|info_hash| info_hash != handshake.info_hash) {
+                return Err(anyhow::anyhow!("Handshake doesn't match the encrypted one"));
+            }
+            Ok((stream, handshake))
+        })
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out waiting for handshake"))??;
+
+        let info_hash = handshake.info_hash;
+        let Some(tx) = self.torrents.lock().unwrap().get(&info_hash).cloned() else {
+            return Err(anyhow::anyhow!(
+                "Unknown info hash {}",
+                hex::encode(info_hash)
+            ));
+        };
+        let incoming = IncomingPeer {
+            peer,
+            stream,
+            handshake,
+            permit,
+        };
+        match tx.try_send(incoming) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(anyhow::anyhow!("Too many peers waiting")),
+            Err(TrySendError::Closed(_)) => {
+                // the torrent's swarm is gone, unless another one has taken its place since
+                let mut torrents = self.torrents.lock().unwrap();
+                if torrents.get(&info_hash).is_some_and(Sender::is_closed) {
+                    torrents.remove(&info_hash);
+                }
+                Err(anyhow::anyhow!(
+                    "Unknown info hash {}",
+                    hex::encode(info_hash)
+                ))
+            }
+        }
+    }
+}
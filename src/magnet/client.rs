@@ -1,30 +1,71 @@
-use std::collections::HashMap;
+use std::{net::SocketAddr, time::Duration};
 
 use anyhow::Error;
+use sha1::{Digest, Sha1};
+use tokio::time::{timeout, timeout_at, Instant};
 
 use crate::{
     handshake::HandshakeMessage,
-    magnet::magnet::MagnetLink,
-    peer_messages::{ExtensionPayload, MessageId},
-    tcp::TcpManager,
-    torrent::torrent::Info,
+    magnet::{
+        magnet::MagnetLink,
+        metadata::{self, MetadataMessage, MetadataMessageType, UT_METADATA_ID},
+    },
+    peer_messages::MessageId,
+    tcp::{ConnectionOptions, TcpManager},
+    torrent::{
+        events::{Event, Events},
+        torrent::Info,
+    },
 };
 
+// how long a peer gets to answer our BitTorrent handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// how long a peer can go without sending a metadata piece before we try another one
+const METADATA_PIECE_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct MagnetClient {
     pub magnet: MagnetLink,
     client: TcpManager,
+    options: ConnectionOptions,
+    peers: Vec<SocketAddr>,
+    peer_index: usize,
+    metadata_size: Option<usize>,
+    // peers we give up on are reported as disconnects
+    events: Events,
 }
 
 impl MagnetClient {
-    pub async fn new(magnet: MagnetLink) -> Self {
-        let peers = magnet.fetch_peers().await.unwrap();
-        let client = TcpManager::connect(peers[0]).await;
-        Self { magnet, client }
+    pub async fn new(
+        magnet: MagnetLink,
+        options: ConnectionOptions,
+        events: Events,
+    ) -> Result<Self, Error> {
+        let peers = magnet.fetch_peers().await?;
+        let info_hash = magnet.get_info_hash();
+        for (peer_index, peer) in peers.iter().enumerate() {
+            match TcpManager::connect_with(*peer, info_hash, &options).await {
+                Ok(client) => {
+                    return Ok(Self {
+                        magnet,
+                        client,
+                        options,
+                        peers,
+                        peer_index,
+                        metadata_size: None,
+                        events,
+                    })
+                }
+                Err(e) => peer_failed(&events, *peer, e),
+            }
+        }
+        Err(anyhow::anyhow!("Failed to connect to any peer"))
     }
 
     pub async fn extension_handshake(&mut self) -> Result<(String, u8), Error> {
         let handshake_message = HandshakeMessage::new(self.magnet.get_info_hash(), true);
-        let handshake_resp = self.client.handshake(handshake_message).await?;
+        let handshake_resp = timeout(HANDSHAKE_TIMEOUT, self.client.handshake(handshake_message))
+            .await
+            .map_err(|_| anyhow::anyhow!("Handshake timed out"))??;
 
         if handshake_resp.reserved[5] != 16 {
             // this is mandatory for extension handshake
@@ -35,39 +76,155 @@ impl MagnetClient {
 
         let peer_id = hex::encode(handshake_resp.peer_id);
 
-        let (msg_id, _payload) = self.client.read_message().await?;
-        assert_eq!(msg_id, MessageId::Bitfield);
-
-        let extension_handshake_payload = self.client.extension_handshake().await?;
+        let extension_handshake_payload = self.client.extension_handshake(None).await?;
         let extension_id = extension_handshake_payload.get_extension_id() as u8;
+        self.metadata_size = extension_handshake_payload.get_metadata_size();
 
         Ok((peer_id, extension_id))
     }
 
     pub async fn fetch_metadata_info(&mut self, extension_id: u8) -> Result<Info, Error> {
-        let msg_body = HashMap::from([("msg_type".to_string(), 0), ("piece".to_string(), 0)]);
-        let mut msg = vec![extension_id];
-        msg.extend(serde_bencode::to_bytes(&msg_body).unwrap());
+        let mut extension_id = extension_id;
+        loop {
+            match self.fetch_metadata_from_peer(extension_id).await {
+                Ok(bytes) => return Ok(Info::from_bytes(&bytes)),
+                Err(e) => {
+                    let peer = self.peers[self.peer_index];
+                    peer_failed(
+                        &self.events,
+                        peer,
+                        anyhow::anyhow!("Failed to fetch metadata: {}", e),
+                    );
+                    extension_id = self.connect_next_peer().await?;
+                }
+            }
+        }
+    }
 
-        self.client.send_message(MessageId::Extension, msg).await?;
+    async fn connect_next_peer(&mut self) -> Result<u8, Error> {
+        while self.peer_index + 1 < self.peers.len() {
+            self.peer_index += 1;
+            let peer = self.peers[self.peer_index];
+            let info_hash = self.magnet.get_info_hash();
+            let client = match TcpManager::connect_with(peer, info_hash, &self.options).await {
+                Ok(client) => client,
+                Err(e) => {
+                    peer_failed(&self.events, peer, e);
+                    continue;
+                }
+            };
+            self.client = client;
+            match self.extension_handshake().await {
+                Ok((_peer_id, extension_id)) => return Ok(extension_id),
+                Err(e) => peer_failed(
+                    &self.events,
+                    peer,
+                    anyhow::anyhow!("Extension handshake failed: {}", e),
+                ),
+            }
+        }
+        Err(anyhow::anyhow!("No more peers to fetch metadata from"))
+    }
+
+    async fn fetch_metadata_from_peer(&mut self, extension_id: u8) -> Result<Vec<u8>, Error> {
+        if extension_id == 0 {
+            return Err(anyhow::anyhow!("Peer does not support ut_metadata"));
+        }
+        let metadata_size = self
+            .metadata_size
+            .ok_or_else(|| anyhow::anyhow!("Peer did not advertise metadata_size"))?;
+        if metadata_size == 0 || metadata_size > metadata::MAX_METADATA_SIZE {
+            return Err(anyhow::anyhow!(
+                "Peer advertised an invalid metadata_size {}",
+                metadata_size
+            ));
+        }
+        let piece_count = metadata::piece_count(metadata_size);
 
-        let (msg_id, payload) = self.client.read_message().await?;
-        assert_eq!(msg_id, MessageId::Extension);
+        for piece in 0..piece_count {
+            let msg = MetadataMessage::request(piece as u32).to_bytes(extension_id, &[]);
+            self.client.send_message(MessageId::Extension, msg).await?;
+        }
 
-        let (header, data) = split_header_and_data(&payload).unwrap();
+        let mut metadata = vec![0u8; metadata_size];
+        let mut received = vec![false; piece_count];
+        let mut remaining = piece_count;
+        let mut deadline = Instant::now() + METADATA_PIECE_TIMEOUT;
+        while remaining > 0 {
+            let (msg_id, payload) = timeout_at(deadline, self.client.read_message())
+                .await
+                .map_err(|_| anyhow::anyhow!("Timed out waiting for metadata"))??;
+            // peers may interleave other messages (have, unchoke, other extensions)
+            if msg_id != MessageId::Extension || payload.first() != Some(&UT_METADATA_ID) {
+                continue;
+            }
+
+            let (message, data) = MetadataMessage::from_bytes(&payload[1..])?;
+            let piece = message.piece as usize;
+            match message.message_type()? {
+                MetadataMessageType::Reject => {
+                    return Err(anyhow::anyhow!("Peer rejected metadata piece {}", piece));
+                }
+                MetadataMessageType::Request => {
+                    // we don't have the metadata yet either
+                    let reply = metadata::respond(None, message.piece, extension_id);
+                    self.client
+                        .send_message(MessageId::Extension, reply)
+                        .await?;
+                    continue;
+                }
+                MetadataMessageType::Data => {}
+            }
+
+            if piece >= piece_count || received[piece] {
+                continue;
+            }
+            if message
+                .total_size
+                .is_some_and(|size| size as usize != metadata_size)
+            {
+                return Err(anyhow::anyhow!(
+                    "Peer sent inconsistent metadata total_size"
+                ));
+            }
+            if data.len() != metadata::piece_len(metadata_size, piece) {
+                return Err(anyhow::anyhow!(
+                    "Metadata piece {} has invalid length {}",
+                    piece,
+                    data.len()
+                ));
+            }
+
+            let begin = piece * metadata::METADATA_PIECE_SIZE;
+            let Some(slot) = metadata.get_mut(begin..begin + data.len()) else {
+                return Err(anyhow::anyhow!(
+                    "Metadata piece {} doesn't fit in {} bytes",
+                    piece,
+                    metadata_size
+                ));
+            };
+            slot.copy_from_slice(data);
+            received[piece] = true;
+            remaining -= 1;
+            deadline = Instant::now() + METADATA_PIECE_TIMEOUT;
+        }
 
-        let extension_payload = ExtensionPayload::from_bytes(&header);
-        assert_eq!(extension_payload.message_id, 21);
+        let mut hasher = Sha1::new();
+        hasher.update(&metadata);
+        let hash: [u8; 20] = hasher.finalize().into();
+        if hash != self.magnet.get_info_hash() {
+            return Err(anyhow::anyhow!(
+                "Metadata does not match the magnet info hash"
+            ));
+        }
 
-        let info = Info::from_bytes(&data);
-        Ok(info)
+        Ok(metadata)
     }
 }
 
-fn split_header_and_data(message: &[u8]) -> Option<(&[u8], &[u8])> {
-    if let Some(pos) = message.windows(2).position(|window| window == b"ee") {
-        Some((&message[..pos + 2], &message[pos + 2..]))
-    } else {
-        None
-    }
+fn peer_failed(events: &Events, peer: SocketAddr, error: Error) {
+    events.send(Event::PeerDisconnected {
+        peer,
+        error: Some(error.to_string()),
+    });
 }
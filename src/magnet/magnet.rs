@@ -45,7 +45,7 @@ impl MagnetLink {
                 "xt" => {
                     if let Some(hash) = value.strip_prefix("urn:btih:") {
                         result.info_hash = hash.to_string();
-                    } else if let Some(hash) = value.split(':').last() {
+                    } else if let Some(hash) = value.split(':').next_back() {
                         result.info_hash = hash.to_string();
                     }
                 }
@@ -0,0 +1,109 @@
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+
+use crate::bencode;
+
+// ID we advertise for ut_metadata in our extension handshake
+pub const UT_METADATA_ID: u8 = 21;
+pub const METADATA_PIECE_SIZE: usize = 1 << 14;
+// largest metadata we'll fetch; the size comes from the peer, so it's capped before we allocate
+pub const MAX_METADATA_SIZE: usize = 4 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum MetadataMessageType {
+    Request = 0,
+    Data = 1,
+    Reject = 2,
+}
+
+impl TryFrom<u8> for MetadataMessageType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(MetadataMessageType::Request),
+            1 => Ok(MetadataMessageType::Data),
+            2 => Ok(MetadataMessageType::Reject),
+            _ => Err(anyhow::anyhow!("invalid ut_metadata msg_type: {}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetadataMessage {
+    pub msg_type: u8,
+    pub piece: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_size: Option<u32>,
+}
+
+impl MetadataMessage {
+    pub fn request(piece: u32) -> Self {
+        Self {
+            msg_type: MetadataMessageType::Request as u8,
+            piece,
+            total_size: None,
+        }
+    }
+
+    pub fn data(piece: u32, total_size: u32) -> Self {
+        Self {
+            msg_type: MetadataMessageType::Data as u8,
+            piece,
+            total_size: Some(total_size),
+        }
+    }
+
+    pub fn reject(piece: u32) -> Self {
+        Self {
+            msg_type: MetadataMessageType::Reject as u8,
+            piece,
+            total_size: None,
+        }
+    }
+
+    pub fn message_type(&self) -> Result<MetadataMessageType, Error> {
+        MetadataMessageType::try_from(self.msg_type)
+    }
+
+    // Builds an extension message payload: extension id, bencoded dict, then raw piece data.
+    pub fn to_bytes(&self, extension_id: u8, data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![extension_id];
+        bytes.extend(serde_bencode::to_bytes(self).unwrap());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    // Parses an extension message payload (without the leading extension id) into the
+    // dict header and any trailing piece data.
+    pub fn from_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+        let header_len = bencode::value_len(bytes)?;
+        let message: MetadataMessage = serde_bencode::from_bytes(&bytes[..header_len])
+            .map_err(|e| anyhow::anyhow!("Failed to parse ut_metadata message: {}", e))?;
+        Ok((message, &bytes[header_len..]))
+    }
+}
+
+pub fn piece_count(metadata_size: usize) -> usize {
+    metadata_size.div_ceil(METADATA_PIECE_SIZE)
+}
+
+pub fn piece_len(metadata_size: usize, piece: usize) -> usize {
+    let begin = piece * METADATA_PIECE_SIZE;
+    METADATA_PIECE_SIZE.min(metadata_size.saturating_sub(begin))
+}
+
+// Builds the reply to a peer's ut_metadata request: the requested piece when we have the
+// metadata, otherwise a reject.
+pub fn respond(metadata: Option<&[u8]>, piece: u32, extension_id: u8) -> Vec<u8> {
+    match metadata {
+        Some(metadata) if (piece as usize) < piece_count(metadata.len()) => {
+            let begin = piece as usize * METADATA_PIECE_SIZE;
+            let end = begin + piece_len(metadata.len(), piece as usize);
+            MetadataMessage::data(piece, metadata.len() as u32)
+                .to_bytes(extension_id, &metadata[begin..end])
+        }
+        _ => MetadataMessage::reject(piece).to_bytes(extension_id, &[]),
+    }
+}
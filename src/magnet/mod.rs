@@ -1,2 +1,4 @@
 pub mod client;
+#[allow(clippy::module_inception)]
 pub mod magnet;
+pub mod metadata;
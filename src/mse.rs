@@ -0,0 +1,546 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use anyhow::Error;
+use num_bigint::BigUint;
+use rand::{rng, Rng};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+// 768-bit safe prime from the MSE spec, generator 2
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const KEY_LEN: usize = 96;
+const MAX_PAD: usize = 512;
+const VC: [u8; 8] = [0; 8];
+
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum EncryptionPolicy {
+    Disabled,
+    #[default]
+    Prefer,
+    Require,
+}
+
+impl EncryptionPolicy {
+    fn crypto_provide(&self) -> u32 {
+        match self {
+            EncryptionPolicy::Require => CRYPTO_RC4,
+            _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        }
+    }
+
+    fn crypto_select(&self, crypto_provide: u32) -> Result<u32, Error> {
+        if crypto_provide & CRYPTO_RC4 != 0 {
+            Ok(CRYPTO_RC4)
+        } else if crypto_provide & CRYPTO_PLAINTEXT != 0 && *self != EncryptionPolicy::Require {
+            Ok(CRYPTO_PLAINTEXT)
+        } else {
+            Err(anyhow::anyhow!(
+                "No acceptable encryption method offered: {:#x}",
+                crypto_provide
+            ))
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (i, s) in state.iter_mut().enumerate() {
+            *s = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        let mut rc4 = Self { state, i: 0, j: 0 };
+        // the spec discards the first 1024 bytes of keystream
+        rc4.apply(&mut [0u8; 1024]);
+        rc4
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state
+                [self.state[self.i as usize].wrapping_add(self.state[self.j as usize]) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+struct KeyPair {
+    private: BigUint,
+    public: [u8; KEY_LEN],
+}
+
+impl KeyPair {
+    fn generate() -> Self {
+        let prime = prime();
+        let private = BigUint::from_bytes_be(&rng().random::<[u8; 20]>());
+        let public = to_key_bytes(&BigUint::from(2u32).modpow(&private, &prime));
+        Self { private, public }
+    }
+
+    fn shared_secret(&self, remote_public: &[u8]) -> [u8; KEY_LEN] {
+        let remote_public = BigUint::from_bytes_be(remote_public);
+        to_key_bytes(&remote_public.modpow(&self.private, &prime()))
+    }
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME.as_bytes(), 16).unwrap()
+}
+
+fn to_key_bytes(value: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = value.to_bytes_be();
+    let mut key = [0u8; KEY_LEN];
+    key[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(a: [u8; 20], b: [u8; 20]) -> [u8; 20] {
+    let mut out = [0u8; 20];
+    for i in 0..20 {
+        out[i] = a[i] ^ b[i];
+    }
+    out
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = rng();
+    let len = rng.random_range(0..=MAX_PAD);
+    (0..len).map(|_| rng.random()).collect()
+}
+
+// Reads one byte at a time until `pattern` has been seen, giving up after `max_skip` bytes
+// of padding. Reading byte-wise means nothing past the pattern is consumed.
+async fn sync_to<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    pattern: &[u8],
+    max_skip: usize,
+) -> Result<(), Error> {
+    let mut window = Vec::with_capacity(max_skip + pattern.len());
+    while window.len() < max_skip + pattern.len() {
+        window.push(stream.read_u8().await?);
+        if window.ends_with(pattern) {
+            return Ok(());
+        }
+    }
+    Err(anyhow::anyhow!("Failed to synchronize encrypted handshake"))
+}
+
+// Performs the initiating side of the MSE handshake on a freshly connected stream.
+pub async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    info_hash: [u8; 20],
+    policy: EncryptionPolicy,
+) -> Result<MseStream<S>, Error> {
+    let keys = KeyPair::generate();
+    let mut msg = keys.public.to_vec();
+    msg.extend(random_pad());
+    stream.write_all(&msg).await?;
+
+    let mut remote_public = [0u8; KEY_LEN];
+    stream.read_exact(&mut remote_public).await?;
+    let secret = keys.shared_secret(&remote_public);
+
+    let mut encrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut decrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+
+    let mut msg = hash(&[b"req1", &secret]).to_vec();
+    msg.extend(xor(hash(&[b"req2", &info_hash]), hash(&[b"req3", &secret])));
+    let mut encrypted = VC.to_vec();
+    encrypted.extend(policy.crypto_provide().to_be_bytes());
+    encrypted.extend(0u16.to_be_bytes()); // len(PadC)
+    encrypted.extend(0u16.to_be_bytes()); // len(IA)
+    encrypt.apply(&mut encrypted);
+    msg.extend(encrypted);
+    stream.write_all(&msg).await?;
+
+    // the responder's VC is found by encrypting it with the responder's keystream
+    let mut encrypted_vc = VC;
+    decrypt.apply(&mut encrypted_vc);
+    sync_to(&mut stream, &encrypted_vc, MAX_PAD).await?;
+
+    let mut select = [0u8; 6];
+    stream.read_exact(&mut select).await?;
+    decrypt.apply(&mut select);
+    let crypto_select = u32::from_be_bytes(select[0..4].try_into().unwrap());
+    let pad_len = u16::from_be_bytes(select[4..6].try_into().unwrap()) as usize;
+    if pad_len > MAX_PAD {
+        return Err(anyhow::anyhow!("Invalid PadD length {}", pad_len));
+    }
+    let mut pad = vec![0u8; pad_len];
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+
+    if crypto_select & policy.crypto_provide() == 0 || crypto_select.count_ones() != 1 {
+        return Err(anyhow::anyhow!(
+            "Peer selected unsupported encryption method {:#x}",
+            crypto_select
+        ));
+    }
+
+    Ok(MseStream::new(
+        stream,
+        (crypto_select == CRYPTO_RC4).then_some((decrypt, encrypt)),
+        Vec::new(),
+    ))
+}
+
+// Performs the receiving side of the MSE handshake. `received` holds bytes already read
+// from the stream while detecting the protocol. Returns the stream and the info hash the
+// initiator asked for.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    received: &[u8],
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> Result<(MseStream<S>, [u8; 20]), Error> {
+    let mut remote_public = [0u8; KEY_LEN];
+    remote_public[..received.len()].copy_from_slice(received);
+    stream
+        .read_exact(&mut remote_public[received.len()..])
+        .await?;
+
+    let keys = KeyPair::generate();
+    let mut msg = keys.public.to_vec();
+    msg.extend(random_pad());
+    stream.write_all(&msg).await?;
+    let secret = keys.shared_secret(&remote_public);
+
+    sync_to(&mut stream, &hash(&[b"req1", &secret]), MAX_PAD).await?;
+
+    let mut skey_hash = [0u8; 20];
+    stream.read_exact(&mut skey_hash).await?;
+    let skey_hash = xor(skey_hash, hash(&[b"req3", &secret]));
+    let info_hash = *info_hashes
+        .iter()
+        .find(|info_hash| hash(&[b"req2", *info_hash]) == skey_hash)
+        .ok_or_else(|| anyhow::anyhow!("Encrypted handshake for unknown info hash"))?;
+
+    let mut decrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut encrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+
+    let mut header = [0u8; 14];
+    stream.read_exact(&mut header).await?;
+    decrypt.apply(&mut header);
+    if header[0..8] != VC {
+        return Err(anyhow::anyhow!("Invalid verification constant"));
+    }
+    let crypto_provide = u32::from_be_bytes(header[8..12].try_into().unwrap());
+    let pad_len = u16::from_be_bytes(header[12..14].try_into().unwrap()) as usize;
+    if pad_len > MAX_PAD {
+        return Err(anyhow::anyhow!("Invalid PadC length {}", pad_len));
+    }
+    let mut pad = vec![0u8; pad_len + 2];
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+    let ia_len = u16::from_be_bytes(pad[pad_len..].try_into().unwrap()) as usize;
+    let mut initial_payload = vec![0u8; ia_len];
+    stream.read_exact(&mut initial_payload).await?;
+    decrypt.apply(&mut initial_payload);
+
+    let crypto_select = policy.crypto_select(crypto_provide)?;
+    let mut msg = VC.to_vec();
+    msg.extend(crypto_select.to_be_bytes());
+    msg.extend(0u16.to_be_bytes()); // len(PadD)
+    encrypt.apply(&mut msg);
+    stream.write_all(&msg).await?;
+
+    let stream = MseStream::new(
+        stream,
+        (crypto_select == CRYPTO_RC4).then_some((decrypt, encrypt)),
+        initial_payload,
+    );
+    Ok((stream, info_hash))
+}
+
+// A stream that has completed the MSE handshake. With RC4 selected all traffic is
+// encrypted, otherwise it passes through unchanged. Bytes in `prefix` were already read
+// off the wire and are returned before anything else.
+pub struct MseStream<S> {
+    inner: S,
+    ciphers: Option<(Rc4, Rc4)>,
+    prefix: Vec<u8>,
+    prefix_pos: usize,
+    pending: Vec<u8>,
+    pending_pos: usize,
+}
+
+impl<S> MseStream<S> {
+    pub fn plaintext(inner: S, prefix: Vec<u8>) -> Self {
+        Self::new(inner, None, prefix)
+    }
+
+    fn new(inner: S, ciphers: Option<(Rc4, Rc4)>, prefix: Vec<u8>) -> Self {
+        Self {
+            inner,
+            ciphers,
+            prefix,
+            prefix_pos: 0,
+            pending: Vec::new(),
+            pending_pos: 0,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.ciphers.is_some()
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pending_pos < self.pending.len() {
+            let n = ready!(
+                Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.pending_pos..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending_pos += n;
+        }
+        self.pending.clear();
+        self.pending_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.prefix_pos < this.prefix.len() {
+            let n = buf.remaining().min(this.prefix.len() - this.prefix_pos);
+            buf.put_slice(&this.prefix[this.prefix_pos..this.prefix_pos + n]);
+            this.prefix_pos += n;
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some((decrypt, _)) = this.ciphers.as_mut() {
+            decrypt.apply(&mut buf.filled_mut()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.ciphers.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        // once encrypted the keystream has advanced, so the bytes must be kept until written
+        ready!(this.poll_drain(cx))?;
+        let mut data = buf.to_vec();
+        if let Some((_, encrypt)) = this.ciphers.as_mut() {
+            encrypt.apply(&mut data);
+        }
+        this.pending = data;
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::HandshakeMessage;
+    use crate::tcp::{ConnectionOptions, TcpManager, TransportPreference};
+    use tokio::net::{TcpListener, TcpStream};
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+
+    async fn loopback() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (outgoing, incoming) = tokio::join!(
+            TcpStream::connect(listener.local_addr().unwrap()),
+            listener.accept()
+        );
+        (outgoing.unwrap(), incoming.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn initiate_and_accept_agree_on_rc4() {
+        for (initiator, acceptor) in [
+            (EncryptionPolicy::Prefer, EncryptionPolicy::Prefer),
+            (EncryptionPolicy::Prefer, EncryptionPolicy::Require),
+            (EncryptionPolicy::Require, EncryptionPolicy::Prefer),
+            (EncryptionPolicy::Require, EncryptionPolicy::Require),
+        ] {
+            let (outgoing, mut incoming) = loopback().await;
+            let accepting = async {
+                let mut received = [0u8; 20];
+                incoming.read_exact(&mut received).await?;
+                accept(incoming, &received, &[[1; 20], INFO_HASH], acceptor).await
+            };
+            let (initiated, accepted) =
+                tokio::join!(initiate(outgoing, INFO_HASH, initiator), accepting);
+            let mut initiated = initiated.unwrap();
+            let (mut accepted, info_hash) = accepted.unwrap();
+            assert_eq!(info_hash, INFO_HASH);
+            assert!(initiated.is_encrypted() && accepted.is_encrypted());
+
+            initiated.write_all(b"ping").await.unwrap();
+            initiated.flush().await.unwrap();
+            let mut ping = [0u8; 4];
+            accepted.read_exact(&mut ping).await.unwrap();
+            assert_eq!(&ping, b"ping");
+            accepted.write_all(b"pong").await.unwrap();
+            accepted.flush().await.unwrap();
+            let mut pong = [0u8; 4];
+            initiated.read_exact(&mut pong).await.unwrap();
+            assert_eq!(&pong, b"pong");
+        }
+    }
+
+    #[tokio::test]
+    async fn accept_rejects_unknown_info_hash() {
+        let (outgoing, mut incoming) = loopback().await;
+        let accepting = async {
+            let mut received = [0u8; 20];
+            incoming.read_exact(&mut received).await?;
+            accept(incoming, &received, &[[1; 20]], EncryptionPolicy::Prefer).await
+        };
+        let (initiated, accepted) = tokio::join!(
+            initiate(outgoing, INFO_HASH, EncryptionPolicy::Prefer),
+            accepting
+        );
+        assert!(accepted.is_err());
+        assert!(initiated.is_err());
+    }
+
+    #[test]
+    fn selection_follows_policy() {
+        let prefer = EncryptionPolicy::Prefer;
+        let require = EncryptionPolicy::Require;
+        assert_eq!(
+            prefer.crypto_select(CRYPTO_RC4 | CRYPTO_PLAINTEXT).unwrap(),
+            CRYPTO_RC4
+        );
+        assert_eq!(
+            prefer.crypto_select(CRYPTO_PLAINTEXT).unwrap(),
+            CRYPTO_PLAINTEXT
+        );
+        assert!(require.crypto_select(CRYPTO_PLAINTEXT).is_err());
+        assert_eq!(require.crypto_provide(), CRYPTO_RC4);
+    }
+
+    // Runs a whole connection for each pair of policies: Ok(encrypted) if the peers could talk.
+    async fn connect(
+        initiator: EncryptionPolicy,
+        acceptor: EncryptionPolicy,
+    ) -> Result<bool, Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        // a rejected encrypted attempt is followed by a plaintext reconnect
+        let accepting = tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await?;
+                if let Ok((mut manager, _)) =
+                    TcpManager::accept(stream, &[INFO_HASH], acceptor).await
+                {
+                    let handshake = manager.read_handshake().await?;
+                    manager.send_handshake(&handshake).await?;
+                    return Ok::<_, Error>(manager.is_encrypted());
+                }
+            }
+        });
+        let options = ConnectionOptions {
+            encryption: initiator,
+            transport: TransportPreference::Tcp,
+            ..Default::default()
+        };
+        let connecting = async {
+            let mut manager = TcpManager::connect_with(addr, INFO_HASH, &options).await?;
+            let handshake = manager
+                .handshake(HandshakeMessage::new(INFO_HASH, true))
+                .await?;
+            assert_eq!(handshake.info_hash, INFO_HASH);
+            Ok::<_, Error>(manager.is_encrypted())
+        };
+        let result = tokio::time::timeout(std::time::Duration::from_secs(5), connecting)
+            .await
+            .map_err(|_| anyhow::anyhow!("timed out"))?;
+        let encrypted = result?;
+        assert_eq!(accepting.await??, encrypted);
+        Ok(encrypted)
+    }
+
+    #[tokio::test]
+    async fn every_policy_pair() {
+        use EncryptionPolicy::*;
+        for (initiator, acceptor, expected) in [
+            (Disabled, Disabled, Some(false)),
+            (Disabled, Prefer, Some(false)),
+            (Disabled, Require, None),
+            // the acceptor refuses the encrypted handshake, so we fall back to plaintext
+            (Prefer, Disabled, Some(false)),
+            (Prefer, Prefer, Some(true)),
+            (Prefer, Require, Some(true)),
+            (Require, Disabled, None),
+            (Require, Prefer, Some(true)),
+            (Require, Require, Some(true)),
+        ] {
+            let result = connect(initiator, acceptor).await;
+            assert_eq!(
+                result.as_ref().ok().copied(),
+                expected,
+                "{:?} -> {:?}: {:?}",
+                initiator,
+                acceptor,
+                result.err()
+            );
+        }
+    }
+}
@@ -1,14 +1,22 @@
+use std::collections::HashMap;
+
+use anyhow::Error;
+use bytes::{BufMut, Bytes, BytesMut};
 use serde::{Deserialize, Serialize};
 use serde_bencode::value::Value;
 
+use crate::bencode;
+use crate::magnet::metadata::UT_METADATA_ID;
+use crate::pex::UT_PEX_ID;
+
 #[derive(Debug)]
 pub struct PeerMessage {
     pub length: [u8; 4],
     pub message_id: [u8; 1],
-    pub payload: Vec<u8>,
+    pub payload: Bytes,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u8)]
 pub enum MessageId {
     Choke = 0,
@@ -20,7 +28,10 @@ pub enum MessageId {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    Port = 9,
     Extension = 20,
+    // anything we don't implement; ignored rather than treated as an error
+    Unknown = 255,
 }
 
 impl From<u8> for MessageId {
@@ -35,14 +46,16 @@ impl From<u8> for MessageId {
             6 => MessageId::Request,
             7 => MessageId::Piece,
             8 => MessageId::Cancel,
+            9 => MessageId::Port,
             20 => MessageId::Extension,
-            _ => panic!("Invalid message ID: {}", value),
+            _ => MessageId::Unknown,
         }
     }
 }
 
 impl PeerMessage {
-    pub fn new(message_id: MessageId, payload: Vec<u8>) -> Self {
+    pub fn new(message_id: MessageId, payload: impl Into<Bytes>) -> Self {
+        let payload = payload.into();
         let message_id = [message_id as u8];
         let length: [u8; 4] = ((message_id.len() as u32) + (payload.len() as u32)).to_be_bytes();
 
@@ -53,10 +66,11 @@ impl PeerMessage {
         }
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Self {
+    // Takes the id and payload (without the length prefix); the payload shares `bytes`.
+    pub fn from_bytes(bytes: Bytes) -> Self {
         let length = (bytes.len() as u32).to_be_bytes();
         let message_id = bytes[0..1].try_into().unwrap();
-        let payload = bytes[1..].to_vec();
+        let payload = bytes.slice(1..);
         Self {
             length,
             message_id,
@@ -64,12 +78,12 @@ impl PeerMessage {
         }
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        bytes.extend_from_slice(&self.length);
-        bytes.extend_from_slice(&self.message_id);
-        bytes.extend_from_slice(&self.payload);
-        bytes
+    pub fn to_bytes(&self) -> Bytes {
+        let mut bytes = BytesMut::with_capacity(5 + self.payload.len());
+        bytes.put_slice(&self.length);
+        bytes.put_slice(&self.message_id);
+        bytes.put_slice(&self.payload);
+        bytes.freeze()
     }
 }
 
@@ -77,20 +91,29 @@ impl PeerMessage {
 pub struct PiecePayload {
     pub index: u32,
     pub begin: u32,
-    pub block: Vec<u8>,
+    pub block: Bytes,
 }
 
 impl PiecePayload {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
+    // The block shares `bytes` rather than being copied out of it.
+    pub fn from_bytes(bytes: Bytes) -> Self {
         let index = u32::from_be_bytes(bytes[0..4].try_into().expect("Failed to convert index"));
         let begin = u32::from_be_bytes(bytes[4..8].try_into().expect("Failed to convert begin"));
-        let block = bytes[8..].to_vec();
+        let block = bytes.slice(8..);
         Self {
             index,
             begin,
             block,
         }
     }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + self.block.len());
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(&self.begin.to_be_bytes());
+        bytes.extend_from_slice(&self.block);
+        bytes
+    }
 }
 
 #[derive(Debug)]
@@ -109,6 +132,15 @@ impl RequestPayload {
         }
     }
 
+    // Parses a request (or cancel) payload.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 12 {
+            return None;
+        }
+        let field = |i: usize| u32::from_be_bytes(bytes[i..i + 4].try_into().unwrap());
+        Some(Self::new(field(0), field(4), field(8)))
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = vec![];
         bytes.extend_from_slice(&self.index.to_be_bytes());
@@ -118,6 +150,29 @@ impl RequestPayload {
     }
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct ExtensionHandshake {
+    pub m: HashMap<String, u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtensionHandshake {
+    pub fn new(metadata_size: Option<usize>, pex: bool) -> Self {
+        let mut m = HashMap::from([("ut_metadata".to_string(), UT_METADATA_ID)]);
+        if pex {
+            m.insert("ut_pex".to_string(), UT_PEX_ID);
+        }
+        Self { m, metadata_size }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8];
+        bytes.extend(serde_bencode::to_bytes(self).unwrap());
+        bytes
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ExtensionPayload {
     pub message_id: u8,
@@ -125,27 +180,62 @@ pub struct ExtensionPayload {
 }
 
 impl ExtensionPayload {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        let message_id = bytes[0];
-        let payload: Value = serde_bencode::from_bytes(&bytes[1..]).unwrap();
-        Self {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let (&message_id, bytes) = bytes
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("Empty extension message"))?;
+        // checked first so a deeply nested payload can't exhaust the stack while decoding
+        let length = bencode::value_len(bytes)?;
+        let payload: Value = serde_bencode::from_bytes(&bytes[..length])
+            .map_err(|e| anyhow::anyhow!("Failed to parse extension handshake: {}", e))?;
+        Ok(Self {
             message_id,
             payload,
-        }
+        })
     }
 
     pub fn get_extension_id(&self) -> u32 {
-        let extension_id = match &self.payload {
-            Value::Dict(dict) => match dict.get(&b"m".to_vec()) {
-                Some(Value::Dict(inner_dict)) => match inner_dict.get(&b"ut_metadata".to_vec()) {
-                    Some(Value::Int(val)) => *val,
-                    _ => 0,
-                },
-                _ => 0,
-            },
-            _ => 0,
-        };
+        self.get_extension("ut_metadata").unwrap_or(0) as u32
+    }
+
+    pub fn get_extension(&self, name: &str) -> Option<i64> {
+        match self.get_int(b"m", Some(name.as_bytes())) {
+            Some(0) | None => None,
+            id => id,
+        }
+    }
+
+    pub fn get_metadata_size(&self) -> Option<usize> {
+        self.get_int(b"metadata_size", None)
+            .filter(|size| *size > 0)
+            .map(|size| size as usize)
+    }
+
+    // The port the peer listens on, which for a peer that connected to us isn't the one it
+    // connected from.
+    pub fn get_port(&self) -> Option<u16> {
+        self.get_int(b"p", None)
+            .and_then(|port| u16::try_from(port).ok())
+            .filter(|port| *port > 0)
+    }
 
-        extension_id as u32
+    pub fn get_reqq(&self) -> Option<usize> {
+        self.get_int(b"reqq", None)
+            .filter(|reqq| *reqq > 0)
+            .map(|reqq| reqq as usize)
+    }
+
+    fn get_int(&self, key: &[u8], inner_key: Option<&[u8]>) -> Option<i64> {
+        let Value::Dict(dict) = &self.payload else {
+            return None;
+        };
+        match (dict.get(key), inner_key) {
+            (Some(Value::Int(val)), None) => Some(*val),
+            (Some(Value::Dict(inner_dict)), Some(inner_key)) => match inner_dict.get(inner_key) {
+                Some(Value::Int(val)) => Some(*val),
+                _ => None,
+            },
+            _ => None,
+        }
     }
 }
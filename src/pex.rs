@@ -0,0 +1,337 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::bencode;
+
+// ID we advertise for ut_pex in our extension handshake
+pub const UT_PEX_ID: u8 = 1;
+
+pub const FLAG_ENCRYPTION: u8 = 0x01;
+pub const FLAG_SEED: u8 = 0x02;
+pub const FLAG_UTP: u8 = 0x04;
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+// BEP 11: at most one message per minute, with at most 50 added and 50 dropped peers
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+pub const MAX_PEX_PEERS: usize = 50;
+
+const MAX_POOL_SIZE: usize = 1000;
+// how long a peer we've tried is remembered, so tracker and ut_pex updates don't hand it straight
+// back to us
+const RETRY_INTERVAL: Duration = Duration::from_secs(300);
+// minimum time between accepted messages from the same peer
+const MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(45);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PexPeer {
+    pub addr: SocketAddr,
+    pub flags: u8,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PexPayload {
+    #[serde(default)]
+    added: ByteBuf,
+    #[serde(default, rename = "added.f")]
+    added_flags: ByteBuf,
+    #[serde(default)]
+    added6: ByteBuf,
+    #[serde(default, rename = "added6.f")]
+    added6_flags: ByteBuf,
+    #[serde(default)]
+    dropped: ByteBuf,
+    #[serde(default)]
+    dropped6: ByteBuf,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PexMessage {
+    pub added: Vec<PexPeer>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+
+    pub fn to_bytes(&self, extension_id: u8) -> Vec<u8> {
+        let mut payload = PexPayload::default();
+        for peer in &self.added {
+            match peer.addr {
+                SocketAddr::V4(_) => {
+                    payload.added.extend(compact_addr(&peer.addr));
+                    payload.added_flags.push(peer.flags);
+                }
+                SocketAddr::V6(_) => {
+                    payload.added6.extend(compact_addr(&peer.addr));
+                    payload.added6_flags.push(peer.flags);
+                }
+            }
+        }
+        for addr in &self.dropped {
+            match addr {
+                SocketAddr::V4(_) => payload.dropped.extend(compact_addr(addr)),
+                SocketAddr::V6(_) => payload.dropped6.extend(compact_addr(addr)),
+            }
+        }
+
+        let mut bytes = vec![extension_id];
+        bytes.extend(serde_bencode::to_bytes(&payload).unwrap());
+        bytes
+    }
+
+    // Parses an extension message payload (without the leading extension id).
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        // checked first so a deeply nested payload can't exhaust the stack while decoding
+        let length = bencode::value_len(bytes)?;
+        let payload: PexPayload = serde_bencode::from_bytes(&bytes[..length])
+            .map_err(|e| anyhow::anyhow!("Failed to parse ut_pex message: {}", e))?;
+
+        let mut added = Vec::new();
+        for (addrs, flags, len) in [
+            (&payload.added, &payload.added_flags, 6),
+            (&payload.added6, &payload.added6_flags, 18),
+        ] {
+            for (i, chunk) in addrs.chunks_exact(len).enumerate() {
+                added.push(PexPeer {
+                    addr: parse_compact_addr(chunk),
+                    flags: flags.get(i).copied().unwrap_or(0),
+                });
+            }
+        }
+
+        let dropped = payload
+            .dropped
+            .chunks_exact(6)
+            .chain(payload.dropped6.chunks_exact(18))
+            .map(parse_compact_addr)
+            .collect();
+
+        Ok(Self { added, dropped })
+    }
+}
+
+fn compact_addr(addr: &SocketAddr) -> Vec<u8> {
+    let mut bytes = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    bytes.extend_from_slice(&addr.port().to_be_bytes());
+    bytes
+}
+
+fn parse_compact_addr(bytes: &[u8]) -> SocketAddr {
+    let (ip, port) = bytes.split_at(bytes.len() - 2);
+    let ip = match ip.len() {
+        4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(ip).unwrap())),
+        _ => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap())),
+    };
+    SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))
+}
+
+// Tracks what we last told a single peer so that each message only carries the changes.
+#[derive(Debug, Default)]
+pub struct PexState {
+    sent: HashMap<SocketAddr, u8>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl PexState {
+    pub fn next_message(
+        &mut self,
+        connected: &HashMap<SocketAddr, u8>,
+        exclude: Option<SocketAddr>,
+    ) -> Option<PexMessage> {
+        if self
+            .last_sent
+            .is_some_and(|last_sent| last_sent.elapsed() < PEX_INTERVAL)
+        {
+            return None;
+        }
+
+        let added: Vec<PexPeer> = connected
+            .iter()
+            .filter(|(addr, _)| Some(**addr) != exclude && !self.sent.contains_key(addr))
+            .take(MAX_PEX_PEERS)
+            .map(|(addr, flags)| PexPeer {
+                addr: *addr,
+                flags: *flags,
+            })
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .keys()
+            .filter(|addr| !connected.contains_key(addr))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+
+        for peer in &added {
+            self.sent.insert(peer.addr, peer.flags);
+        }
+        for addr in &dropped {
+            self.sent.remove(addr);
+        }
+        self.last_sent = Some(Instant::now());
+
+        let message = PexMessage { added, dropped };
+        (!message.is_empty()).then_some(message)
+    }
+
+    // Returns false when the peer is sending messages faster than BEP 11 allows.
+    pub fn accept_message(&mut self) -> bool {
+        if self
+            .last_received
+            .is_some_and(|last_received| last_received.elapsed() < MIN_RECEIVE_INTERVAL)
+        {
+            return false;
+        }
+        self.last_received = Some(Instant::now());
+        true
+    }
+}
+
+// Swarm-wide set of peers we could connect to, fed by the tracker and by ut_pex.
+#[derive(Debug, Default)]
+pub struct PeerPool {
+    candidates: VecDeque<SocketAddr>,
+    // every peer we have or had, with when we last tried it; None while it's still a candidate
+    known: HashMap<SocketAddr, Option<Instant>>,
+    connected: HashMap<SocketAddr, u8>,
+    // addresses that sent us corrupt data; never handed out again
+    banned: HashSet<IpAddr>,
+}
+
+impl PeerPool {
+    pub fn new(peers: Vec<SocketAddr>) -> Self {
+        let mut pool = Self::default();
+        pool.add_peers(peers);
+        pool
+    }
+
+    pub fn add_peers(&mut self, peers: impl IntoIterator<Item = SocketAddr>) {
+        self.forget_stale();
+        for peer in peers {
+            if self.banned.contains(&peer.ip()) || self.known.contains_key(&peer) {
+                continue;
+            }
+            if self.known.len() >= MAX_POOL_SIZE && !self.forget_oldest() {
+                break;
+            }
+            self.known.insert(peer, None);
+            self.candidates.push_back(peer);
+        }
+    }
+
+    // Forgets peers we tried long enough ago that they can be candidates again.
+    fn forget_stale(&mut self) {
+        let connected = &self.connected;
+        self.known.retain(|peer, tried| {
+            connected.contains_key(peer) || tried.is_none_or(|at| at.elapsed() < RETRY_INTERVAL)
+        });
+    }
+
+    // Makes room by forgetting the peer we tried longest ago. False if every known peer is
+    // connected or still waiting to be tried.
+    fn forget_oldest(&mut self) -> bool {
+        let oldest = self
+            .known
+            .iter()
+            .filter(|(peer, _)| !self.connected.contains_key(peer))
+            .filter_map(|(peer, tried)| tried.map(|at| (at, *peer)))
+            .min();
+        match oldest {
+            Some((_, peer)) => {
+                self.known.remove(&peer);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn add_pex_message(&mut self, message: &PexMessage) {
+        self.add_peers(
+            message
+                .added
+                .iter()
+                .take(MAX_PEX_PEERS)
+                .map(|peer| peer.addr),
+        );
+    }
+
+    pub fn next_candidate(&mut self) -> Option<SocketAddr> {
+        while let Some(peer) = self.candidates.pop_front() {
+            self.known.insert(peer, Some(Instant::now()));
+            if !self.connected.contains_key(&peer) && !self.banned.contains(&peer.ip()) {
+                return Some(peer);
+            }
+        }
+        None
+    }
+
+    pub fn mark_connected(&mut self, peer: SocketAddr, flags: u8) {
+        self.known.insert(peer, Some(Instant::now()));
+        self.connected.insert(peer, flags);
+    }
+
+    pub fn mark_disconnected(&mut self, peer: SocketAddr) {
+        self.connected.remove(&peer);
+        // the retry interval starts once the connection is over
+        self.known.insert(peer, Some(Instant::now()));
+    }
+
+    // Bans every port on `ip`; false if it already was. Connected peers there find out
+    // through `is_banned`.
+    pub fn ban(&mut self, ip: IpAddr) -> bool {
+        self.banned.insert(ip)
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.contains(&ip)
+    }
+
+    pub fn connected_peers(&self) -> &HashMap<SocketAddr, u8> {
+        &self.connected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_added_and_dropped_peers() {
+        let message = PexMessage {
+            added: vec![
+                PexPeer {
+                    addr: "10.0.0.1:6881".parse().unwrap(),
+                    flags: FLAG_SEED | FLAG_REACHABLE,
+                },
+                PexPeer {
+                    addr: "[2001:db8::1]:51413".parse().unwrap(),
+                    flags: FLAG_UTP,
+                },
+            ],
+            dropped: vec!["10.0.0.2:6882".parse().unwrap()],
+        };
+        let bytes = message.to_bytes(UT_PEX_ID);
+        assert_eq!(bytes[0], UT_PEX_ID);
+        assert_eq!(PexMessage::from_bytes(&bytes[1..]).unwrap(), message);
+    }
+
+    #[test]
+    fn refuses_deeply_nested_payload() {
+        let mut bytes = b"d1:x".to_vec();
+        bytes.extend(std::iter::repeat_n(b'l', 200_000));
+        assert!(PexMessage::from_bytes(&bytes).is_err());
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+// Most bytes a single read or write moves before paying for them. Keeping it small (one
+// block) means a busy peer can't get far ahead of the others sharing a bucket.
+const QUANTUM: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+// A limit in bytes per second that can be changed while connections are using it. 0 means
+// unlimited.
+#[derive(Debug, Clone, Default)]
+struct Rate(Arc<AtomicU64>);
+
+impl Rate {
+    fn new(bytes_per_second: u64) -> Self {
+        Self(Arc::new(AtomicU64::new(bytes_per_second)))
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, bytes_per_second: u64) {
+        self.0.store(bytes_per_second, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Default)]
+struct TokenBucket {
+    rate: Rate,
+    // tokens and when they were last topped up; negative while in debt
+    state: Mutex<Option<(f64, Instant)>>,
+}
+
+impl TokenBucket {
+    fn new(rate: Rate) -> Self {
+        Self {
+            rate,
+            state: Mutex::new(None),
+        }
+    }
+
+    // Pays for `bytes` that were just moved, going into debt if there aren't enough tokens,
+    // and returns how long the connection should wait until the debt is paid off.
+    fn take(&self, bytes: usize) -> Duration {
+        let rate = self.rate.get();
+        let mut state = self.state.lock().unwrap();
+        if rate == 0 {
+            *state = None;
+            return Duration::ZERO;
+        }
+        let now = Instant::now();
+        // a second's worth of burst, but always at least one quantum
+        let capacity = rate.max(QUANTUM as u64) as f64;
+        let (tokens, updated) = state.get_or_insert((capacity, now));
+        let elapsed = now.duration_since(*updated).as_secs_f64();
+        *tokens = (*tokens + elapsed * rate as f64).min(capacity) - bytes as f64;
+        *updated = now;
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / rate as f64)
+        }
+    }
+}
+
+// An upload and a download bucket.
+#[derive(Debug, Default)]
+pub struct Bandwidth {
+    upload: TokenBucket,
+    download: TokenBucket,
+}
+
+impl Bandwidth {
+    // Fresh buckets that follow this one's limits, including later changes to them.
+    fn sharing_limits(&self) -> Self {
+        Self {
+            upload: TokenBucket::new(self.upload.rate.clone()),
+            download: TokenBucket::new(self.download.rate.clone()),
+        }
+    }
+
+    fn bucket(&self, direction: Direction) -> &TokenBucket {
+        match direction {
+            Direction::Upload => &self.upload,
+            Direction::Download => &self.download,
+        }
+    }
+
+    pub fn limit(&self, direction: Direction) -> u64 {
+        self.bucket(direction).rate.get()
+    }
+
+    // Takes effect immediately for every connection sharing the bucket; 0 removes the limit.
+    pub fn set_limit(&self, direction: Direction, bytes_per_second: u64) {
+        self.bucket(direction).rate.set(bytes_per_second);
+    }
+}
+
+// Global, per-torrent and per-peer bandwidth limits. Clones share the same buckets, so the
+// limits can be changed at runtime through any of them.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    global: Arc<Bandwidth>,
+    torrent: Arc<Bandwidth>,
+    // only the limits are used; each peer connection gets its own buckets following them
+    peer: Arc<Bandwidth>,
+    exempt_lan: Arc<AtomicBool>,
+}
+
+impl RateLimits {
+    pub fn global(&self) -> &Bandwidth {
+        &self.global
+    }
+
+    pub fn torrent(&self) -> &Bandwidth {
+        &self.torrent
+    }
+
+    pub fn peer(&self) -> &Bandwidth {
+        &self.peer
+    }
+
+    pub fn exempt_lan(&self) -> bool {
+        self.exempt_lan.load(Ordering::Relaxed)
+    }
+
+    // When set, peers on loopback, private or link-local addresses aren't limited at all.
+    pub fn set_exempt_lan(&self, exempt: bool) {
+        self.exempt_lan.store(exempt, Ordering::Relaxed);
+    }
+
+    // Limits for another torrent: the same global and per-peer limits, and its own torrent
+    // bucket starting out with this one's limits.
+    pub fn for_torrent(&self) -> Self {
+        let torrent = Bandwidth {
+            upload: TokenBucket::new(Rate::new(self.torrent.limit(Direction::Upload))),
+            download: TokenBucket::new(Rate::new(self.torrent.limit(Direction::Download))),
+        };
+        Self {
+            torrent: Arc::new(torrent),
+            ..self.clone()
+        }
+    }
+
+    pub fn limit<S>(&self, peer: SocketAddr, stream: S) -> RateLimitedStream<S> {
+        RateLimitedStream {
+            inner: stream,
+            peer: self.peer.sharing_limits(),
+            limits: self.clone(),
+            lan: is_lan(peer.ip()),
+            read_wait: None,
+            write_wait: None,
+        }
+    }
+}
+
+fn is_lan(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_lan(IpAddr::V4(ip)),
+            // loopback, unique local (fc00::/7) and link-local (fe80::/10)
+            None => {
+                ip.is_loopback()
+                    || ip.segments()[0] & 0xfe00 == 0xfc00
+                    || ip.segments()[0] & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+// A peer connection that pays for the bytes it moves from its own, its torrent's and the
+// global buckets. Bytes are paid for after they move and the next read or write waits out
+// any debt, so nothing already read is held back.
+pub struct RateLimitedStream<S> {
+    inner: S,
+    peer: Bandwidth,
+    limits: RateLimits,
+    lan: bool,
+    read_wait: Option<Pin<Box<Sleep>>>,
+    write_wait: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> RateLimitedStream<S> {
+    fn charge(&self, direction: Direction, bytes: usize) -> Option<Pin<Box<Sleep>>> {
+        if bytes == 0 || (self.lan && self.limits.exempt_lan()) {
+            return None;
+        }
+        let wait = [&self.peer, &*self.limits.torrent, &*self.limits.global]
+            .iter()
+            .map(|bandwidth| bandwidth.bucket(direction).take(bytes))
+            .max()
+            .unwrap_or_default();
+        if wait.is_zero() {
+            None
+        } else {
+            Some(Box::pin(tokio::time::sleep(wait)))
+        }
+    }
+}
+
+// Polls a pending wait, clearing it once it's over.
+fn poll_wait(wait: &mut Option<Pin<Box<Sleep>>>, cx: &mut Context<'_>) -> Poll<()> {
+    if let Some(sleep) = wait {
+        if sleep.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        *wait = None;
+    }
+    Poll::Ready(())
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RateLimitedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if poll_wait(&mut self.read_wait, cx).is_pending() {
+            return Poll::Pending;
+        }
+        let limit = buf.remaining().min(QUANTUM);
+        let mut limited = buf.take(limit);
+        match Pin::new(&mut self.inner).poll_read(cx, &mut limited) {
+            Poll::Ready(Ok(())) => {}
+            other => return other,
+        }
+        let read = limited.filled().len();
+        // safety: `limited` borrows `buf`'s unfilled part, and the inner reader filled `read`
+        // bytes of it
+        unsafe { buf.assume_init(read) };
+        buf.advance(read);
+        self.read_wait = self.charge(Direction::Download, read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RateLimitedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if poll_wait(&mut self.write_wait, cx).is_pending() {
+            return Poll::Pending;
+        }
+        let limit = buf.len().min(QUANTUM);
+        let written = match Pin::new(&mut self.inner).poll_write(cx, &buf[..limit]) {
+            Poll::Ready(Ok(written)) => written,
+            other => return other,
+        };
+        self.write_wait = self.charge(Direction::Upload, written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
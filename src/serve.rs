@@ -0,0 +1,472 @@
+use std::{
+    io::SeekFrom,
+    net::SocketAddr,
+    ops::Range,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Error;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::torrent::{
+    reader::{BackgroundDownload, TorrentReader},
+    swarm::Swarm,
+};
+
+// longest request head we'll read before giving up on a client
+const MAX_HEAD: usize = 16 * 1024;
+
+// Serves a torrent's files over HTTP while they download. Nothing is fetched until the first
+// request for file content; from then on the whole torrent downloads in the background, with
+// the pieces each response is waiting for (and a read-ahead window after them) first.
+pub struct HttpServer {
+    swarm: Arc<Swarm>,
+    names: Vec<String>,
+    lengths: Vec<u64>,
+    download: Mutex<Option<Arc<BackgroundDownload>>>,
+}
+
+impl HttpServer {
+    pub fn new(swarm: Swarm, names: Vec<String>) -> Self {
+        let lengths = {
+            let storage = swarm.storage();
+            let storage = storage.lock().unwrap();
+            storage
+                .layout()
+                .files
+                .iter()
+                .map(|file| file.length)
+                .collect()
+        };
+        Self {
+            swarm: Arc::new(swarm),
+            names,
+            lengths,
+            download: Mutex::new(None),
+        }
+    }
+
+    pub async fn run(self: Arc<Self>, listener: TcpListener) -> Result<(), Error> {
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.handle(stream).await {
+                    eprintln!("HTTP client {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    // Starts the background download on first use, and again if it failed, say when every
+    // peer dropped, so later requests don't all fail the same way.
+    fn download(&self) -> Arc<BackgroundDownload> {
+        let mut download = self.download.lock().unwrap();
+        match download.as_ref() {
+            Some(running) if !running.failed() => running.clone(),
+            _ => {
+                let pieces = (0..self.piece_count()).collect();
+                let started = BackgroundDownload::start(self.swarm.clone(), pieces);
+                *download = Some(started.clone());
+                started
+            }
+        }
+    }
+
+    async fn open(&self, file: usize, start: u64) -> Result<TorrentReader, Error> {
+        let mut reader =
+            TorrentReader::with_download(self.swarm.clone(), Some(file), self.download())?;
+        reader.seek(SeekFrom::Start(start)).await?;
+        Ok(reader)
+    }
+
+    fn piece_count(&self) -> u32 {
+        self.swarm.storage().lock().unwrap().layout().piece_count as u32
+    }
+
+    async fn handle(&self, stream: TcpStream) -> Result<(), Error> {
+        let mut stream = BufReader::new(stream);
+        let Some(request) = Request::read(&mut stream).await? else {
+            return Ok(());
+        };
+        let stream = stream.get_mut();
+        if request.method != "GET" && request.method != "HEAD" {
+            return respond(stream, "405 Method Not Allowed", &[], b"").await;
+        }
+        if request.path == "/" {
+            return respond(
+                stream,
+                "200 OK",
+                &[("Content-Type", "text/html")],
+                self.index().as_bytes(),
+            )
+            .await;
+        }
+        let name = urlencoding::decode(request.path.trim_start_matches('/'))?;
+        let Some(file) = self.names.iter().position(|candidate| *candidate == name) else {
+            return respond(stream, "404 Not Found", &[], b"").await;
+        };
+
+        let length = self.lengths[file];
+        let range = match request
+            .range
+            .as_deref()
+            .map(|range| parse_range(range, length))
+        {
+            None | Some(Ok(None)) => None,
+            Some(Ok(Some(range))) => Some(range),
+            Some(Err(())) => {
+                let unsatisfiable = format!("bytes */{}", length);
+                return respond(
+                    stream,
+                    "416 Range Not Satisfiable",
+                    &[("Content-Range", &unsatisfiable)],
+                    b"",
+                )
+                .await;
+            }
+        };
+        let (status, body) = match &range {
+            Some(range) => ("206 Partial Content", range.clone()),
+            None => ("200 OK", 0..length),
+        };
+        // opened before anything is sent, so a failure can still get an error status
+        let reader = if request.method == "HEAD" || body.is_empty() {
+            None
+        } else {
+            match self.open(file, body.start).await {
+                Ok(reader) => Some(reader),
+                Err(e) => {
+                    respond(
+                        stream,
+                        "500 Internal Server Error",
+                        &[("Content-Type", "text/plain; charset=utf-8")],
+                        e.to_string().as_bytes(),
+                    )
+                    .await?;
+                    return Err(e);
+                }
+            }
+        };
+        let mut head = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\nConnection: close\r\n",
+            status,
+            content_type(&name),
+            body.end - body.start
+        );
+        if range.is_some() {
+            head.push_str(&format!(
+                "Content-Range: bytes {}-{}/{}\r\n",
+                body.start,
+                body.end - 1,
+                length
+            ));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).await?;
+        let Some(reader) = reader else {
+            return Ok(());
+        };
+        tokio::io::copy(&mut reader.take(body.end - body.start), stream).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    fn index(&self) -> String {
+        let mut html = String::from("<!DOCTYPE html>\n<ul>\n");
+        for (name, length) in self.names.iter().zip(&self.lengths) {
+            html.push_str(&format!(
+                "<li><a href=\"/{}\">{}</a> ({} bytes)</li>\n",
+                urlencoding::encode(name).replace("%2F", "/"),
+                escape_html(name),
+                length
+            ));
+        }
+        html.push_str("</ul>\n");
+        html
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    range: Option<String>,
+}
+
+impl Request {
+    // None if the client closed the connection without sending anything.
+    async fn read(stream: &mut BufReader<TcpStream>) -> Result<Option<Self>, Error> {
+        let mut line = String::new();
+        if (&mut *stream)
+            .take(MAX_HEAD as u64)
+            .read_line(&mut line)
+            .await?
+            == 0
+        {
+            return Ok(None);
+        }
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            return Err(anyhow::anyhow!("Malformed request line"));
+        };
+        let mut request = Self {
+            method: method.to_string(),
+            // the query string doesn't pick anything
+            path: target.split('?').next().unwrap_or_default().to_string(),
+            range: None,
+        };
+
+        let mut read = line.len();
+        loop {
+            line.clear();
+            let limit = MAX_HEAD.saturating_sub(read) as u64;
+            read += (&mut *stream).take(limit).read_line(&mut line).await?;
+            if !line.ends_with('\n') {
+                return Err(anyhow::anyhow!("Request head too large or cut short"));
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                return Ok(Some(request));
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("range") {
+                    request.range = Some(value.trim().to_string());
+                }
+            }
+        }
+    }
+}
+
+// Parses a `Range` header against a body of `length` bytes. Ok(None) means serve the whole
+// body: the header is in a form we don't handle, like several ranges, which HTTP lets us
+// ignore. Err means the range can't be satisfied.
+fn parse_range(header: &str, length: u64) -> Result<Option<Range<u64>>, ()> {
+    let Some(spec) = header.strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // bytes=-N is the last N bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => length.saturating_sub(suffix)..length,
+        (Ok(start), Err(_)) if end.is_empty() => start..length,
+        (Ok(start), Ok(end)) if start <= end => start..(end + 1).min(length),
+        _ => return Ok(None),
+    };
+    if range.start >= length || range.is_empty() {
+        return Err(());
+    }
+    Ok(Some(range))
+}
+
+fn content_type(name: &str) -> &'static str {
+    let extension = Path::new(name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "txt" | "nfo" => "text/plain; charset=utf-8",
+        "html" | "htm" => "text/html",
+        "json" => "application/json",
+        "pdf" => "application/pdf",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        _ => "application/octet-stream",
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<(), Error> {
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status,
+        body.len()
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+// Binds `addr` and serves until the listener fails.
+pub async fn serve(server: HttpServer, addr: SocketAddr) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr).await?;
+    println!("Serving on http://{}", listener.local_addr()?);
+    Arc::new(server).run(listener).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{memory::MemoryStorage, Storage};
+    use crate::tcp::ConnectionOptions;
+    use crate::torrent::torrent::{Info, Torrent};
+    use serde_bytes::ByteBuf;
+    use sha1::{Digest, Sha1};
+    use std::time::Duration;
+
+    const PIECE_LENGTH: usize = 16 * 1024;
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok(Some(0..100)));
+        assert_eq!(parse_range("bytes=500-", 1000), Ok(Some(500..1000)));
+        assert_eq!(parse_range("bytes=-100", 1000), Ok(Some(900..1000)));
+        // an end past the body is cut short, a suffix longer than it is the whole body
+        assert_eq!(parse_range("bytes=900-5000", 1000), Ok(Some(900..1000)));
+        assert_eq!(parse_range("bytes=-5000", 1000), Ok(Some(0..1000)));
+    }
+
+    #[test]
+    fn ignores_ranges_it_doesnt_handle() {
+        assert_eq!(parse_range("items=0-99", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=0-9,20-29", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=50-10", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=abc", 1000), Ok(None));
+    }
+
+    #[test]
+    fn refuses_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(parse_range("bytes=1000-1999", 1000), Err(()));
+        assert_eq!(parse_range("bytes=-0", 1000), Err(()));
+        assert_eq!(parse_range("bytes=0-", 0), Err(()));
+    }
+
+    // A server for a one-file torrent with no peers, whose pieces are all already in storage
+    // if `stored`.
+    fn http_server(data: &[u8], stored: bool) -> HttpServer {
+        let pieces: Vec<u8> = data
+            .chunks(PIECE_LENGTH)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        let info = Info {
+            name: "clip.mp4".to_string(),
+            length: Some(data.len() as u64),
+            files: None,
+            pieces: ByteBuf::from(pieces),
+            piece_length: PIECE_LENGTH as u32,
+            private: None,
+            bytes: None,
+        };
+        let torrent = Torrent::new("http://127.0.0.1:1/announce".to_string(), info);
+        let mut storage = MemoryStorage::new(&torrent);
+        if stored {
+            for (piece, data) in data.chunks(PIECE_LENGTH).enumerate() {
+                storage.write_piece(piece as u32, data).unwrap();
+            }
+        }
+        let swarm = Swarm::new(
+            torrent,
+            vec![],
+            ConnectionOptions::default(),
+            Arc::new(Mutex::new(storage)),
+        );
+        if stored {
+            let picker = swarm.picker();
+            let mut picker = picker.lock().unwrap();
+            for piece in 0..data.len().div_ceil(PIECE_LENGTH) {
+                picker.piece_verified(piece as u32);
+            }
+        }
+        HttpServer::new(swarm, vec!["clip.mp4".to_string()])
+    }
+
+    async fn listen(server: HttpServer) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Arc::new(server).run(listener));
+        addr
+    }
+
+    // Sends a GET for `path` and returns the response head, each line ending in CRLF, and body.
+    async fn get(addr: SocketAddr, path: &str, range: &str) -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nRange: {}\r\n\r\n", path, range);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await.unwrap();
+        let split = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+        let head = String::from_utf8(response[..split + 2].to_vec()).unwrap();
+        (head, response[split + 4..].to_vec())
+    }
+
+    #[tokio::test]
+    async fn serves_range_requests() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let addr = listen(http_server(&data, true)).await;
+
+        // spans a piece boundary
+        let (head, body) = get(addr, "/clip.mp4", "bytes=16000-17999").await;
+        assert!(
+            head.starts_with("HTTP/1.1 206 Partial Content\r\n"),
+            "{}",
+            head
+        );
+        assert!(head.contains("Content-Range: bytes 16000-17999/100000\r\n"));
+        assert!(head.contains("Content-Length: 2000\r\n"));
+        assert!(head.contains("Content-Type: video/mp4\r\n"));
+        assert_eq!(body, data[16000..18000]);
+
+        let (head, body) = get(addr, "/clip.mp4", "bytes=-10").await;
+        assert!(head.contains("Content-Range: bytes 99990-99999/100000\r\n"));
+        assert_eq!(body, data[99990..]);
+
+        let (head, body) = get(addr, "/clip.mp4", "bytes=100000-").await;
+        assert!(
+            head.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"),
+            "{}",
+            head
+        );
+        assert!(head.contains("Content-Range: bytes */100000\r\n"));
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn restarts_a_failed_download() {
+        // no peers and a tracker that refuses connections, so the download fails quickly
+        let server = http_server(&[1; 1000], false);
+        let first = server.download();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !first.failed() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(!Arc::ptr_eq(&first, &server.download()));
+    }
+}
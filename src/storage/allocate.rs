@@ -0,0 +1,113 @@
+use std::{fs::File, path::Path};
+
+use anyhow::Error;
+
+// How output files get their space before the download starts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum AllocationMode {
+    // files grow as pieces are written
+    None,
+    // files are set to their full length up front without reserving disk blocks
+    #[default]
+    Sparse,
+    // disk blocks are reserved up front, so the download can't run out of space midway
+    Full,
+}
+
+// Grows (or shrinks) `file` to `length` according to `mode`. Existing data is kept.
+pub fn allocate(file: &File, length: u64, mode: AllocationMode) -> Result<(), Error> {
+    match mode {
+        AllocationMode::None => Ok(()),
+        AllocationMode::Sparse => Ok(file.set_len(length)?),
+        AllocationMode::Full => {
+            if file.metadata()?.len() > length {
+                file.set_len(length)?;
+            }
+            reserve(file, length)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn reserve(file: &File, length: u64) -> Result<(), Error> {
+    use std::os::fd::AsRawFd;
+
+    if length == 0 {
+        return Ok(());
+    }
+    // safety: the fd is owned by `file` and stays open for the duration of the call
+    let result = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, length as libc::off_t) };
+    if result == 0 {
+        return Ok(());
+    }
+    let error = std::io::Error::last_os_error();
+    // some filesystems can't preallocate; fall back to a sparse file rather than failing
+    if error.raw_os_error() == Some(libc::EOPNOTSUPP) {
+        eprintln!("Filesystem doesn't support preallocation, using a sparse file");
+        return Ok(file.set_len(length)?);
+    }
+    Err(anyhow::anyhow!("Failed to preallocate: {}", error))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reserve(file: &File, length: u64) -> Result<(), Error> {
+    Ok(file.set_len(length)?)
+}
+
+// Bytes the file already occupies on disk; holes in a sparse file don't count.
+pub fn allocated(file: &File) -> Result<u64, Error> {
+    let metadata = file.metadata()?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Ok((metadata.blocks() * 512).min(metadata.len()))
+    }
+    #[cfg(not(unix))]
+    Ok(metadata.len())
+}
+
+// Fails if the filesystem holding `path` doesn't have room for each file to grow to the length
+// paired with it.
+pub fn check_free_space<'a>(
+    path: &Path,
+    files: impl IntoIterator<Item = (&'a File, u64)>,
+) -> Result<(), Error> {
+    let mut needed = 0;
+    for (file, length) in files {
+        needed += length.saturating_sub(allocated(file)?);
+    }
+    let dir = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    match free_space(dir)? {
+        Some(free) if free < needed => Err(anyhow::anyhow!(
+            "Not enough disk space: need {} more bytes, {} available",
+            needed,
+            free
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Free space available to us on the filesystem holding `path`, if we can tell.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> Result<Option<u64>, Error> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // safety: `path` is a valid C string and `stats` is only read after statvfs fills it in
+    let stats = unsafe {
+        if libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        stats.assume_init()
+    };
+    Ok(Some(stats.f_bavail as u64 * stats.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> Result<Option<u64>, Error> {
+    Ok(None)
+}
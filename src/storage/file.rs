@@ -0,0 +1,270 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Error;
+
+use super::{
+    allocate::{self, AllocationMode},
+    Span, Storage, StorageLayout,
+};
+use crate::torrent::torrent::Torrent;
+
+// Plain files on disk, one per file in the torrent. Files that aren't selected are never
+// created; the parts of their data that share a piece with a selected file go to a side
+// partfile, `<root>.parts`, so those pieces can still be verified.
+pub struct FileStorage {
+    layout: StorageLayout,
+    paths: Vec<PathBuf>,
+    // None for files that aren't selected
+    files: Vec<Option<File>>,
+    allocation: AllocationMode,
+    part: Option<File>,
+    // slot in the partfile of each piece that straddles a selected and an unselected file
+    part_slots: HashMap<u32, u64>,
+}
+
+impl FileStorage {
+    pub fn create(root: PathBuf, torrent: &Torrent) -> Result<Self, Error> {
+        let selected = vec![true; torrent.get_files().len()];
+        Self::create_selected(root, torrent, &selected)
+    }
+
+    pub fn create_selected(
+        root: PathBuf,
+        torrent: &Torrent,
+        selected: &[bool],
+    ) -> Result<Self, Error> {
+        let layout = StorageLayout::new(torrent);
+        let paths = layout.paths(&root)?;
+        let mut files = Vec::new();
+        for (path, selected) in paths.iter().zip(selected) {
+            if !selected {
+                files.push(None);
+                continue;
+            }
+            files.push(Some(open(path)?));
+        }
+
+        let mut part_slots = HashMap::new();
+        for (entry, _) in layout
+            .files
+            .iter()
+            .zip(selected)
+            .filter(|(entry, selected)| !**selected && entry.length > 0)
+        {
+            // only a file's first and last pieces can be shared with its neighbours
+            let first = (entry.offset / layout.piece_length) as u32;
+            let last = ((entry.offset + entry.length - 1) / layout.piece_length) as u32;
+            for piece in [first, last] {
+                let size = layout.piece_size(piece) as u64;
+                let shared = layout
+                    .spans(piece, 0, size)?
+                    .iter()
+                    .any(|span| selected[span.file]);
+                if shared && !part_slots.contains_key(&piece) {
+                    part_slots.insert(piece, part_slots.len() as u64);
+                }
+            }
+        }
+        let part = if part_slots.is_empty() {
+            None
+        } else {
+            let mut path = root.into_os_string();
+            path.push(".parts");
+            Some(open(Path::new(&path))?)
+        };
+
+        Ok(Self {
+            layout,
+            paths,
+            files,
+            allocation: AllocationMode::default(),
+            part,
+            part_slots,
+        })
+    }
+
+    pub fn set_allocation(&mut self, allocation: AllocationMode) {
+        self.allocation = allocation;
+    }
+
+    // Fails if the filesystem doesn't have room for the rest of the download.
+    fn check_free_space(&self) -> Result<(), Error> {
+        let Some(path) = self
+            .paths
+            .iter()
+            .zip(&self.files)
+            .find_map(|(path, file)| file.as_ref().map(|_| path))
+        else {
+            return Ok(());
+        };
+        let files = self
+            .files
+            .iter()
+            .zip(&self.layout.files)
+            .filter_map(|(file, entry)| Some((file.as_ref()?, entry.length)));
+        // the partfile grows to a whole piece for each slot
+        let part_length = self.part_slots.len() as u64 * self.layout.piece_length;
+        let part = self.part.as_ref().map(|part| (part, part_length));
+        allocate::check_free_space(path, files.chain(part))
+    }
+
+    // Where a span of the given piece lives: the file itself, or its piece's slot in the
+    // partfile if the file isn't selected.
+    fn locate(&mut self, piece: u32, offset: u32, span: &Span) -> Result<(&mut File, u64), Error> {
+        if let Some(file) = &mut self.files[span.file] {
+            return Ok((file, span.file_offset));
+        }
+        match (&mut self.part, self.part_slots.get(&piece)) {
+            (Some(part), Some(slot)) => Ok((
+                part,
+                slot * self.layout.piece_length + offset as u64 + span.range.start as u64,
+            )),
+            _ => Err(anyhow::anyhow!(
+                "{} is not selected",
+                self.paths[span.file].display()
+            )),
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<File, Error> {
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)?;
+    }
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        // existing data is kept so an interrupted download can resume
+        .truncate(false)
+        .open(path)
+        .map_err(|e| anyhow::anyhow!("Failed to create {}: {}", path.display(), e))
+}
+
+impl Storage for FileStorage {
+    fn layout(&self) -> &StorageLayout {
+        &self.layout
+    }
+
+    fn write_block(&mut self, piece: u32, offset: u32, data: &[u8]) -> Result<(), Error> {
+        for span in self.layout.spans(piece, offset, data.len() as u64)? {
+            let (file, position) = self.locate(piece, offset, &span)?;
+            file.seek(SeekFrom::Start(position))?;
+            file.write_all(&data[span.range.clone()]).map_err(|e| {
+                anyhow::anyhow!("Failed to write {}: {}", self.paths[span.file].display(), e)
+            })?;
+        }
+        Ok(())
+    }
+
+    fn read_block(&mut self, piece: u32, offset: u32, length: u32) -> Result<Vec<u8>, Error> {
+        let mut data = vec![0; length as usize];
+        for span in self.layout.spans(piece, offset, length as u64)? {
+            let (file, position) = self.locate(piece, offset, &span)?;
+            file.seek(SeekFrom::Start(position))?;
+            file.read_exact(&mut data[span.range.clone()])
+                .map_err(|e| {
+                    anyhow::anyhow!("Failed to read {}: {}", self.paths[span.file].display(), e)
+                })?;
+        }
+        Ok(data)
+    }
+
+    // Files aren't buffered, so this just makes sure what was written survives a crash.
+    fn flush(&mut self) -> Result<(), Error> {
+        for file in self.files.iter().chain([&self.part]).flatten() {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn allocate(&mut self) -> Result<(), Error> {
+        self.check_free_space()?;
+        for ((file, entry), path) in self.files.iter().zip(&self.layout.files).zip(&self.paths) {
+            let Some(file) = file else {
+                continue;
+            };
+            allocate::allocate(file, entry.length, self.allocation)
+                .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::torrent;
+
+    fn content() -> Vec<u8> {
+        (1..=66).collect()
+    }
+
+    #[test]
+    fn keeps_unselected_parts_of_shared_pieces_in_the_partfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("dir");
+        let selected = [true, false, false, true, false];
+        let mut storage =
+            FileStorage::create_selected(root.clone(), &torrent(), &selected).unwrap();
+        let content = content();
+
+        storage.write_piece(0, &content[..16]).unwrap();
+        storage.write_block(2, 6, &content[38..48]).unwrap();
+        storage.write_block(2, 0, &content[32..38]).unwrap();
+        // pieces with nothing but unselected data have nowhere to go
+        assert!(storage.write_piece(1, &content[16..32]).is_err());
+        assert!(storage.read_piece(3).is_err());
+        storage.flush().unwrap();
+
+        assert_eq!(storage.read_piece(0).unwrap(), content[..16]);
+        assert_eq!(storage.read_piece(2).unwrap(), content[32..48]);
+        assert_eq!(fs::read(root.join("a.txt")).unwrap(), content[..10]);
+        assert_eq!(
+            fs::read(root.join("extras/deep/c.txt")).unwrap(),
+            content[40..46]
+        );
+        for unselected in ["extras/b.bin", "extras/empty", "d.bin"] {
+            assert!(!root.join(unselected).exists());
+        }
+
+        // a slot per shared piece, each laid out like the piece itself
+        let parts = fs::read(dir.path().join("dir.parts")).unwrap();
+        assert_eq!(parts.len(), 32);
+        assert_eq!(parts[..10], [0; 10]);
+        assert_eq!(parts[10..16], content[10..16]);
+        assert_eq!(parts[16..24], content[32..40]);
+        assert_eq!(parts[24..30], [0; 6]);
+        assert_eq!(parts[30..32], content[46..48]);
+    }
+
+    #[test]
+    fn needs_no_partfile_when_every_file_is_selected() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("dir");
+        let mut storage = FileStorage::create(root.clone(), &torrent()).unwrap();
+        let content = content();
+        for piece in 0..5 {
+            let start = piece as usize * 16;
+            let end = (start + 16).min(content.len());
+            storage.write_piece(piece, &content[start..end]).unwrap();
+        }
+
+        assert_eq!(storage.read_range(8..42).unwrap(), content[8..42]);
+        assert_eq!(
+            fs::read(root.join("extras/b.bin")).unwrap(),
+            content[10..40]
+        );
+        assert!(fs::read(root.join("extras/empty")).unwrap().is_empty());
+        assert_eq!(fs::read(root.join("d.bin")).unwrap(), content[46..]);
+        assert!(!dir.path().join("dir.parts").exists());
+    }
+}
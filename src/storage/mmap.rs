@@ -0,0 +1,128 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    path::PathBuf,
+};
+
+use anyhow::Error;
+use memmap2::MmapMut;
+
+use super::{
+    allocate::{self, AllocationMode},
+    Storage, StorageLayout,
+};
+use crate::torrent::torrent::Torrent;
+
+// Files mapped into memory, so reads and writes are plain copies and the OS does the I/O.
+// Each file is sized to its full length and mapped the first time it's used, since only that
+// much can be mapped; existing data is kept. Unlike `FileStorage`, every file is downloaded.
+pub struct MmapStorage {
+    layout: StorageLayout,
+    paths: Vec<PathBuf>,
+    files: Vec<File>,
+    // None until the file is first used
+    maps: Vec<Option<MmapMut>>,
+    allocation: AllocationMode,
+}
+
+impl MmapStorage {
+    pub fn create(root: PathBuf, torrent: &Torrent) -> Result<Self, Error> {
+        let layout = StorageLayout::new(torrent);
+        let paths = layout.paths(&root)?;
+        let mut files = Vec::new();
+        for path in &paths {
+            if let Some(parent) = path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+            {
+                fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .map_err(|e| anyhow::anyhow!("Failed to create {}: {}", path.display(), e))?;
+            files.push(file);
+        }
+        Ok(Self {
+            maps: paths.iter().map(|_| None).collect(),
+            layout,
+            paths,
+            files,
+            allocation: AllocationMode::default(),
+        })
+    }
+
+    // Files are full length once mapped, so `None` leaves them sparse just like `Sparse`.
+    pub fn set_allocation(&mut self, allocation: AllocationMode) {
+        self.allocation = allocation;
+    }
+
+    // Spans never cover empty files, which can't be mapped.
+    fn map(&mut self, file: usize) -> Result<&mut MmapMut, Error> {
+        if self.maps[file].is_none() {
+            let (path, length) = (&self.paths[file], self.layout.files[file].length);
+            if self.files[file].metadata()?.len() != length {
+                self.files[file].set_len(length)?;
+            }
+            // safety: the file is ours for the lifetime of the storage; other processes
+            // modifying it underneath us would only corrupt data, which hash checks catch
+            let map = unsafe { MmapMut::map_mut(&self.files[file]) }
+                .map_err(|e| anyhow::anyhow!("Failed to map {}: {}", path.display(), e))?;
+            self.maps[file] = Some(map);
+        }
+        Ok(self.maps[file].as_mut().unwrap())
+    }
+}
+
+impl Storage for MmapStorage {
+    fn layout(&self) -> &StorageLayout {
+        &self.layout
+    }
+
+    fn write_block(&mut self, piece: u32, offset: u32, data: &[u8]) -> Result<(), Error> {
+        for span in self.layout.spans(piece, offset, data.len() as u64)? {
+            let map = self.map(span.file)?;
+            let start = span.file_offset as usize;
+            map[start..start + span.range.len()].copy_from_slice(&data[span.range]);
+        }
+        Ok(())
+    }
+
+    fn read_block(&mut self, piece: u32, offset: u32, length: u32) -> Result<Vec<u8>, Error> {
+        let mut data = vec![0; length as usize];
+        for span in self.layout.spans(piece, offset, length as u64)? {
+            let map = self.map(span.file)?;
+            let start = span.file_offset as usize;
+            data[span.range.clone()].copy_from_slice(&map[start..start + span.range.len()]);
+        }
+        Ok(data)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        for map in self.maps.iter().flatten() {
+            map.flush()?;
+        }
+        Ok(())
+    }
+
+    // Writing to a mapped hole on a full disk kills the process rather than failing, so
+    // running out of space has to be caught here.
+    fn allocate(&mut self) -> Result<(), Error> {
+        let Some(path) = self.paths.first() else {
+            return Ok(());
+        };
+        let lengths = self.layout.files.iter().map(|entry| entry.length);
+        allocate::check_free_space(path, self.files.iter().zip(lengths))?;
+        let allocation = match self.allocation {
+            AllocationMode::Full => AllocationMode::Full,
+            _ => AllocationMode::Sparse,
+        };
+        for ((file, entry), path) in self.files.iter().zip(&self.layout.files).zip(&self.paths) {
+            allocate::allocate(file, entry.length, allocation)
+                .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        }
+        Ok(())
+    }
+}
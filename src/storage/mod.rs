@@ -0,0 +1,329 @@
+use std::{
+    ops::Range,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::Error;
+use sha1::{Digest, Sha1};
+
+use crate::torrent::torrent::Torrent;
+
+pub mod allocate;
+pub mod file;
+pub mod memory;
+pub mod mmap;
+pub mod resume;
+pub mod select;
+pub mod verify;
+
+// Which storage downloads to disk write through.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum StorageBackend {
+    // reads and writes are file system calls
+    #[default]
+    File,
+    // files are memory-mapped
+    Mmap,
+}
+
+// Where piece data lives. Offsets are always relative to the start of a piece; the layout
+// takes care of pieces that straddle file boundaries.
+pub trait Storage: Send {
+    fn layout(&self) -> &StorageLayout;
+
+    fn write_block(&mut self, piece: u32, offset: u32, data: &[u8]) -> Result<(), Error>;
+
+    fn read_block(&mut self, piece: u32, offset: u32, length: u32) -> Result<Vec<u8>, Error>;
+
+    fn flush(&mut self) -> Result<(), Error>;
+
+    // Reserves room for the whole torrent up front.
+    fn allocate(&mut self) -> Result<(), Error>;
+
+    fn read_piece(&mut self, piece: u32) -> Result<Vec<u8>, Error> {
+        let length = self.layout().piece_size(piece);
+        self.read_block(piece, 0, length)
+    }
+
+    fn write_piece(&mut self, piece: u32, data: &[u8]) -> Result<(), Error> {
+        self.write_block(piece, 0, data)
+    }
+
+    // Reads bytes by their position in the torrent's content rather than by piece.
+    fn read_range(&mut self, range: Range<u64>) -> Result<Vec<u8>, Error> {
+        let piece_length = self.layout().piece_length;
+        let mut data = Vec::with_capacity((range.end - range.start) as usize);
+        let mut position = range.start;
+        while position < range.end {
+            let offset = position % piece_length;
+            let length = (piece_length - offset).min(range.end - position);
+            data.extend(self.read_block(
+                (position / piece_length) as u32,
+                offset as u32,
+                length as u32,
+            )?);
+            position += length;
+        }
+        Ok(data)
+    }
+
+    // Checks the stored piece against its SHA-1 hash from the torrent.
+    fn verify(&mut self, piece: u32, hash: &[u8]) -> Result<bool, Error> {
+        self.verify_rest(piece, Sha1::new(), 0, hash)
+    }
+
+    // Like `verify`, given a hasher that has already seen the piece's first `hashed` bytes;
+    // only the rest is read back.
+    fn verify_rest(
+        &mut self,
+        piece: u32,
+        mut hasher: Sha1,
+        hashed: u32,
+        hash: &[u8],
+    ) -> Result<bool, Error> {
+        let size = self.layout().piece_size(piece);
+        if hashed < size {
+            hasher.update(self.read_block(piece, hashed, size - hashed)?);
+        }
+        Ok(hasher.finalize().as_slice() == hash)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    // relative to the download root; empty for a single-file torrent
+    pub path: PathBuf,
+    // position of the file's first byte in the torrent's concatenated data
+    pub offset: u64,
+    pub length: u64,
+}
+
+// A contiguous part of a block that lives in one file.
+#[derive(Debug)]
+pub struct Span {
+    pub file: usize,
+    // range within the block's data
+    pub range: Range<usize>,
+    pub file_offset: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct StorageLayout {
+    pub piece_length: u64,
+    pub total_length: u64,
+    pub piece_count: usize,
+    pub files: Vec<FileEntry>,
+}
+
+impl StorageLayout {
+    pub fn new(torrent: &Torrent) -> Self {
+        let mut files = Vec::new();
+        let mut offset = 0;
+        for (path, length) in torrent.get_files() {
+            files.push(FileEntry {
+                path,
+                offset,
+                length,
+            });
+            offset += length;
+        }
+        Self {
+            piece_length: torrent.info.piece_length as u64,
+            total_length: offset,
+            piece_count: torrent.get_piece_count(),
+            files,
+        }
+    }
+
+    pub fn piece_size(&self, piece: u32) -> u32 {
+        let start = piece as u64 * self.piece_length;
+        self.piece_length
+            .min(self.total_length.saturating_sub(start)) as u32
+    }
+
+    pub fn spans(&self, piece: u32, offset: u32, length: u64) -> Result<Vec<Span>, Error> {
+        let start = piece as u64 * self.piece_length + offset as u64;
+        let end = start + length;
+        if offset as u64 + length > self.piece_size(piece) as u64 {
+            return Err(anyhow::anyhow!(
+                "Block {}+{} is outside piece {}",
+                offset,
+                length,
+                piece
+            ));
+        }
+        Ok(self
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| {
+                // an empty file inside the block has nothing to read or write
+                file.length > 0 && file.offset < end && file.offset + file.length > start
+            })
+            .map(|(i, file)| {
+                let from = start.max(file.offset);
+                let to = end.min(file.offset + file.length);
+                Span {
+                    file: i,
+                    range: (from - start) as usize..(to - start) as usize,
+                    file_offset: from - file.offset,
+                }
+            })
+            .collect())
+    }
+
+    // Pieces holding any part of `range` of the torrent's content.
+    pub fn pieces_in(&self, range: &Range<u64>) -> Result<Vec<u32>, Error> {
+        if range.start > range.end || range.end > self.total_length {
+            return Err(anyhow::anyhow!(
+                "Range {}..{} is outside the torrent's {} bytes",
+                range.start,
+                range.end,
+                self.total_length
+            ));
+        }
+        if range.is_empty() {
+            return Ok(Vec::new());
+        }
+        let first = range.start / self.piece_length;
+        let last = (range.end - 1) / self.piece_length;
+        Ok((first as u32..=last as u32).collect())
+    }
+
+    // Turns `range` within one file into a range of the torrent's content.
+    pub fn file_range(&self, file: usize, range: Range<u64>) -> Result<Range<u64>, Error> {
+        let Some(entry) = self.files.get(file) else {
+            return Err(anyhow::anyhow!("No file {} in torrent", file));
+        };
+        if range.start > range.end || range.end > entry.length {
+            return Err(anyhow::anyhow!(
+                "Range {}..{} is outside the file's {} bytes",
+                range.start,
+                range.end,
+                entry.length
+            ));
+        }
+        Ok(entry.offset + range.start..entry.offset + range.end)
+    }
+
+    // Absolute path of each file. A single-file torrent is stored at `root` itself, a
+    // multi-file torrent as a tree of files below `root`.
+    pub fn paths(&self, root: &Path) -> Result<Vec<PathBuf>, Error> {
+        self.files
+            .iter()
+            .map(|file| join_safe(root, &file.path))
+            .collect()
+    }
+}
+
+// Joins a path from the torrent onto the download root, refusing anything that could escape
+// it (absolute paths, `..`).
+fn join_safe(root: &Path, relative: &Path) -> Result<PathBuf, Error> {
+    if relative.as_os_str().is_empty() {
+        return Ok(root.to_path_buf());
+    }
+    if relative
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_)))
+    {
+        return Err(anyhow::anyhow!(
+            "Refusing unsafe path in torrent: {}",
+            relative.display()
+        ));
+    }
+    Ok(root.join(relative))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::torrent::torrent::Info;
+
+    // 16-byte pieces over five files, most pieces straddling two or three of them:
+    //
+    //   piece  0: a.txt 0..10, extras/b.bin 0..6
+    //   piece  1: extras/b.bin 6..22
+    //   piece  2: extras/b.bin 22..30, (extras/empty), extras/deep/c.txt 0..6, d.bin 0..2
+    //   piece  3: d.bin 2..18
+    //   piece  4: d.bin 18..20
+    pub(crate) fn torrent() -> Torrent {
+        let info = [
+            &b"d5:filesl"[..],
+            b"d6:lengthi10e4:pathl5:a.txtee",
+            b"d6:lengthi30e4:pathl6:extras5:b.binee",
+            b"d6:lengthi0e4:pathl6:extras5:emptyee",
+            b"d6:lengthi6e4:pathl6:extras4:deep5:c.txtee",
+            b"d6:lengthi20e4:pathl5:d.binee",
+            b"e4:name3:dir12:piece lengthi16e6:pieces100:",
+            &[0; 100],
+            b"e",
+        ]
+        .concat();
+        Torrent::new("url".to_string(), Info::from_bytes(&info))
+    }
+
+    fn spans(
+        layout: &StorageLayout,
+        piece: u32,
+        offset: u32,
+        length: u64,
+    ) -> Vec<(usize, Range<usize>, u64)> {
+        layout
+            .spans(piece, offset, length)
+            .unwrap()
+            .into_iter()
+            .map(|span| (span.file, span.range, span.file_offset))
+            .collect()
+    }
+
+    #[test]
+    fn lays_files_out_end_to_end() {
+        let layout = StorageLayout::new(&torrent());
+        assert_eq!(layout.total_length, 66);
+        assert_eq!(layout.piece_count, 5);
+        let offsets: Vec<u64> = layout.files.iter().map(|file| file.offset).collect();
+        assert_eq!(offsets, [0, 10, 40, 40, 46]);
+        assert_eq!(layout.piece_size(3), 16);
+        assert_eq!(layout.piece_size(4), 2);
+        assert_eq!(
+            layout.paths(Path::new("/downloads")).unwrap()[3],
+            Path::new("/downloads/extras/deep/c.txt")
+        );
+    }
+
+    #[test]
+    fn splits_blocks_at_file_boundaries() {
+        let layout = StorageLayout::new(&torrent());
+        assert_eq!(spans(&layout, 0, 0, 16), [(0, 0..10, 0), (1, 10..16, 0)]);
+        assert_eq!(spans(&layout, 1, 0, 16), [(1, 0..16, 6)]);
+        assert_eq!(spans(&layout, 1, 4, 8), [(1, 0..8, 10)]);
+        assert_eq!(
+            spans(&layout, 2, 6, 10),
+            [(1, 0..2, 28), (3, 2..8, 0), (4, 8..10, 0)]
+        );
+        assert_eq!(spans(&layout, 2, 8, 6), [(3, 0..6, 0)]);
+        assert_eq!(spans(&layout, 4, 0, 2), [(4, 0..2, 18)]);
+    }
+
+    #[test]
+    fn refuses_blocks_outside_their_piece() {
+        let layout = StorageLayout::new(&torrent());
+        assert!(layout.spans(1, 10, 7).is_err());
+        assert!(layout.spans(4, 0, 3).is_err());
+        assert!(layout.spans(4, 2, 1).is_err());
+        assert!(layout.spans(5, 0, 1).is_err());
+    }
+
+    #[test]
+    fn maps_file_ranges_to_pieces() {
+        let layout = StorageLayout::new(&torrent());
+        let range = layout.file_range(3, 0..6).unwrap();
+        assert_eq!(range, 40..46);
+        assert_eq!(layout.pieces_in(&range).unwrap(), [2]);
+        assert_eq!(layout.pieces_in(&(15..17)).unwrap(), [0, 1]);
+        assert!(layout.pieces_in(&(0..0)).unwrap().is_empty());
+        assert!(layout.pieces_in(&(60..67)).is_err());
+        assert!(layout.file_range(3, 0..7).is_err());
+        assert!(layout.file_range(5, 0..0).is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+
+use super::StorageLayout;
+use crate::torrent::{bitfield::Bitfield, torrent::Torrent};
+
+// Size and modification time of a file when the record was written. If either changed since,
+// the record can't be trusted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub length: u64,
+    // nanoseconds since the epoch
+    pub modified: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ResumeData {
+    info_hash: String,
+    // hex-encoded bitfield of verified pieces
+    pieces: String,
+    // None for files that don't exist, which aren't selected
+    files: Vec<Option<FileStamp>>,
+    // received block offsets of pieces that were still in progress
+    partial: BTreeMap<u32, Vec<u32>>,
+}
+
+// Progress of one download, kept next to it as `<save path>.resume`.
+pub struct ResumeFile {
+    path: PathBuf,
+    info_hash: String,
+    piece_count: usize,
+    layout: StorageLayout,
+    files: Vec<PathBuf>,
+}
+
+impl ResumeFile {
+    pub fn new(save_path: &Path, torrent: &Torrent) -> Result<Self, Error> {
+        let mut path = save_path.as_os_str().to_owned();
+        path.push(".resume");
+        let layout = StorageLayout::new(torrent);
+        Ok(Self {
+            path: path.into(),
+            info_hash: hex::encode(torrent.get_info_hash()),
+            piece_count: torrent.get_piece_count(),
+            files: layout.paths(save_path)?,
+            layout,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Current length of each file, or None if it doesn't exist.
+    pub fn lengths(&self) -> Vec<Option<u64>> {
+        self.files
+            .iter()
+            .map(|path| fs::metadata(path).ok().map(|metadata| metadata.len()))
+            .collect()
+    }
+
+    // Current stamp of each file, or None if it doesn't exist.
+    pub fn stamps(&self) -> Vec<Option<FileStamp>> {
+        self.files
+            .iter()
+            .map(|path| {
+                let metadata = fs::metadata(path).ok()?;
+                Some(FileStamp {
+                    length: metadata.len(),
+                    modified: metadata
+                        .modified()
+                        .ok()
+                        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                        .map_or(0, |since| since.as_nanos() as u64),
+                })
+            })
+            .collect()
+    }
+
+    // The saved verified pieces and partial blocks, as long as the record belongs to this
+    // torrent and none of the files changed since it was written. Writes that came after the
+    // last save (say, before a crash) change the stamps too, so the data gets hash-checked
+    // instead.
+    pub fn load(&self) -> Option<(Bitfield, BTreeMap<u32, Vec<u32>>)> {
+        let bytes = fs::read(&self.path).ok()?;
+        let record: ResumeData = serde_json::from_slice(&bytes).ok()?;
+        if record.info_hash != self.info_hash || record.files != self.stamps() {
+            return None;
+        }
+        let pieces = hex::decode(&record.pieces).ok()?;
+        let pieces = Bitfield::from_bytes(&pieces, self.piece_count).ok()?;
+        let partial = record
+            .partial
+            .into_iter()
+            .filter(|(piece, _)| (*piece as usize) < self.piece_count)
+            .map(|(piece, blocks)| {
+                let size = self.layout.piece_size(piece);
+                let blocks = blocks
+                    .into_iter()
+                    .filter(|begin| *begin < size)
+                    .collect::<Vec<_>>();
+                (piece, blocks)
+            })
+            .filter(|(_, blocks)| !blocks.is_empty())
+            .collect();
+        Some((pieces, partial))
+    }
+
+    // Call after flushing storage, so everything the record lists is on disk and the files are
+    // stamped as they are with it.
+    pub fn save(&self, pieces: &Bitfield, partial: BTreeMap<u32, Vec<u32>>) -> Result<(), Error> {
+        let record = ResumeData {
+            info_hash: self.info_hash.clone(),
+            pieces: hex::encode(pieces.to_bytes()),
+            files: self.stamps(),
+            partial,
+        };
+        // written aside and renamed so a crash mid-save never leaves a torn record
+        let mut temp = self.path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let mut file = File::create(&temp)?;
+        file.write_all(&serde_json::to_vec(&record)?)?;
+        file.sync_all()?;
+        fs::rename(&temp, &self.path)
+            .map_err(|e| anyhow::anyhow!("Failed to save {}: {}", self.path.display(), e))
+    }
+
+    pub fn remove(&self) -> Result<(), Error> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::torrent::Info;
+
+    // a single 40000-byte file in three pieces
+    fn torrent() -> Torrent {
+        let info = [
+            &b"d6:lengthi40000e4:name5:x.bin12:piece lengthi16384e6:pieces60:"[..],
+            &[0; 60],
+            b"e",
+        ]
+        .concat();
+        Torrent::new("url".to_string(), Info::from_bytes(&info))
+    }
+
+    fn progress() -> (Bitfield, BTreeMap<u32, Vec<u32>>) {
+        let mut pieces = Bitfield::new(3);
+        pieces.set(0);
+        (pieces, BTreeMap::from([(1, vec![0])]))
+    }
+
+    #[test]
+    fn loads_what_was_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = dir.path().join("x.bin");
+        fs::write(&save_path, [1; 40000]).unwrap();
+        let resume = ResumeFile::new(&save_path, &torrent()).unwrap();
+        let (pieces, partial) = progress();
+        resume.save(&pieces, partial.clone()).unwrap();
+
+        let (loaded, loaded_partial) = resume.load().unwrap();
+        assert_eq!(loaded.iter().collect::<Vec<_>>(), vec![0]);
+        assert_eq!(loaded_partial, partial);
+    }
+
+    #[test]
+    fn distrusts_files_changed_since_saving() {
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = dir.path().join("x.bin");
+        let file = File::create(&save_path).unwrap();
+        file.set_len(40000).unwrap();
+        let resume = ResumeFile::new(&save_path, &torrent()).unwrap();
+        let (pieces, partial) = progress();
+        resume.save(&pieces, partial).unwrap();
+
+        // edited in place: same length, later modification time
+        let modified = fs::metadata(&save_path).unwrap().modified().unwrap();
+        file.set_modified(modified + std::time::Duration::from_secs(1))
+            .unwrap();
+        assert!(resume.load().is_none());
+
+        resume.save(&pieces, BTreeMap::new()).unwrap();
+        assert!(resume.load().is_some());
+        file.set_len(20000).unwrap();
+        assert!(resume.load().is_none());
+    }
+
+    #[test]
+    fn ignores_records_for_other_torrents() {
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = dir.path().join("x.bin");
+        fs::write(&save_path, [1; 40000]).unwrap();
+        fs::write(
+            dir.path().join("x.bin.resume"),
+            r#"{"info_hash":"00","pieces":"80","files":[],"partial":{}}"#,
+        )
+        .unwrap();
+        let resume = ResumeFile::new(&save_path, &torrent()).unwrap();
+        assert!(resume.load().is_none());
+    }
+}
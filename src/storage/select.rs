@@ -0,0 +1,221 @@
+use anyhow::Error;
+use clap::ValueEnum;
+use regex::Regex;
+
+use super::StorageLayout;
+use crate::torrent::picker::Priority;
+
+// Picks out files by index (`3`) or by a glob over their path (`*.mkv`, `extras/**`). `*`
+// stays within one path component, `**` crosses them.
+#[derive(Debug)]
+pub enum FileSelector {
+    Index(usize),
+    Glob(Regex),
+}
+
+impl FileSelector {
+    pub fn parse(selector: &str) -> Result<Self, Error> {
+        if let Ok(index) = selector.parse() {
+            return Ok(Self::Index(index));
+        }
+        let mut pattern = String::from("^");
+        let mut chars = selector.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    pattern.push_str(".*");
+                }
+                '*' => pattern.push_str("[^/]*"),
+                '?' => pattern.push_str("[^/]"),
+                c => pattern.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        pattern.push('$');
+        Ok(Self::Glob(Regex::new(&pattern)?))
+    }
+
+    pub fn matches(&self, index: usize, path: &str) -> bool {
+        match self {
+            Self::Index(i) => *i == index,
+            Self::Glob(glob) => glob.is_match(path),
+        }
+    }
+}
+
+// The one file `selector` picks out.
+pub fn find_file(names: &[String], selector: &str) -> Result<usize, Error> {
+    let parsed = FileSelector::parse(selector)?;
+    let matches: Vec<usize> = (0..names.len())
+        .filter(|index| parsed.matches(*index, &names[*index]))
+        .collect();
+    match matches[..] {
+        [index] => Ok(index),
+        [] => Err(anyhow::anyhow!("No file matches {}", selector)),
+        _ => Err(anyhow::anyhow!(
+            "{} matches {} files, expected one",
+            selector,
+            matches.len()
+        )),
+    }
+}
+
+// Works out each file's priority. With any `select`ors, files they don't match are skipped;
+// `priorities` are `SELECTOR=LEVEL` pairs applied in order, so later ones win.
+pub fn file_priorities(
+    names: &[String],
+    select: &[String],
+    priorities: &[String],
+) -> Result<Vec<Priority>, Error> {
+    let default = if select.is_empty() {
+        Priority::Normal
+    } else {
+        Priority::Skip
+    };
+    let mut result = vec![default; names.len()];
+    for selector in select {
+        let selector = FileSelector::parse(selector)?;
+        for (index, name) in names.iter().enumerate() {
+            if selector.matches(index, name) {
+                result[index] = Priority::Normal;
+            }
+        }
+    }
+    for rule in priorities {
+        let Some((selector, level)) = rule.rsplit_once('=') else {
+            return Err(anyhow::anyhow!("Expected SELECTOR=LEVEL, got {}", rule));
+        };
+        let selector = FileSelector::parse(selector)?;
+        let level = Priority::from_str(level, true).map_err(|e| anyhow::anyhow!(e))?;
+        for (index, name) in names.iter().enumerate() {
+            if selector.matches(index, name) {
+                result[index] = level;
+            }
+        }
+    }
+    Ok(result)
+}
+
+impl StorageLayout {
+    // A piece gets the highest priority of the files it covers, so it's only skipped when
+    // every one of them is.
+    pub fn piece_priorities(&self, file_priorities: &[Priority]) -> Vec<Priority> {
+        let mut pieces = vec![Priority::Skip; self.piece_count];
+        for (file, priority) in self.files.iter().zip(file_priorities) {
+            if file.length == 0 {
+                continue;
+            }
+            let first = file.offset / self.piece_length;
+            let last = (file.offset + file.length - 1) / self.piece_length;
+            for piece in first..=last {
+                pieces[piece as usize] = pieces[piece as usize].max(*priority);
+            }
+        }
+        pieces
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::torrent;
+
+    fn names() -> Vec<String> {
+        torrent().get_file_names()
+    }
+
+    fn matching(selector: &str) -> Vec<usize> {
+        let selector = FileSelector::parse(selector).unwrap();
+        let names = names();
+        (0..names.len())
+            .filter(|index| selector.matches(*index, &names[*index]))
+            .collect()
+    }
+
+    #[test]
+    fn single_stars_stay_within_a_directory() {
+        assert_eq!(matching("*.txt"), [0]);
+        assert_eq!(matching("**.txt"), [0, 3]);
+        assert_eq!(matching("extras/*"), [1, 2]);
+        assert_eq!(matching("extras/**"), [1, 2, 3]);
+        assert_eq!(matching("extras/*/c.txt"), [3]);
+        assert_eq!(matching("?.bin"), [4]);
+        assert_eq!(matching("extras?b.bin"), Vec::<usize>::new());
+        // everything else is literal
+        assert_eq!(matching("a.txt"), [0]);
+        assert_eq!(matching("a.tx"), Vec::<usize>::new());
+        assert_eq!(matching("[a].txt"), Vec::<usize>::new());
+        assert_eq!(matching("3"), [3]);
+    }
+
+    #[test]
+    fn finds_exactly_one_file() {
+        assert_eq!(find_file(&names(), "**/c.txt").unwrap(), 3);
+        assert_eq!(find_file(&names(), "4").unwrap(), 4);
+        assert!(find_file(&names(), "extras/*").is_err());
+        assert!(find_file(&names(), "*.iso").is_err());
+    }
+
+    #[test]
+    fn later_priorities_win_over_selection() {
+        let select = ["extras/**".to_string()];
+        let priorities = ["*.bin=high".to_string(), "extras/b.bin=low".to_string()];
+        assert_eq!(
+            file_priorities(&names(), &select, &priorities).unwrap(),
+            [
+                Priority::Skip,
+                Priority::Low,
+                Priority::Normal,
+                Priority::Normal,
+                Priority::High
+            ]
+        );
+        assert_eq!(
+            file_priorities(&names(), &[], &[]).unwrap(),
+            [Priority::Normal; 5]
+        );
+        assert!(file_priorities(&names(), &[], &["*.bin".to_string()]).is_err());
+        assert!(file_priorities(&names(), &[], &["*.bin=urgent".to_string()]).is_err());
+    }
+
+    #[test]
+    fn pieces_take_the_highest_priority_of_their_files() {
+        let layout = StorageLayout::new(&torrent());
+        let pieces = layout.piece_priorities(&[
+            Priority::Normal,
+            Priority::Skip,
+            Priority::High,
+            Priority::Skip,
+            Priority::Low,
+        ]);
+        // the empty file doesn't lift the piece it sits in
+        assert_eq!(
+            pieces,
+            [
+                Priority::Normal,
+                Priority::Skip,
+                Priority::Low,
+                Priority::Low,
+                Priority::Low
+            ]
+        );
+
+        let pieces = layout.piece_priorities(&[
+            Priority::Skip,
+            Priority::Skip,
+            Priority::Skip,
+            Priority::High,
+            Priority::Skip,
+        ]);
+        assert_eq!(
+            pieces,
+            [
+                Priority::Skip,
+                Priority::Skip,
+                Priority::High,
+                Priority::Skip,
+                Priority::Skip
+            ]
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Error;
+use sha1::{Digest, Sha1};
+
+use super::StorageLayout;
+use crate::torrent::torrent::Torrent;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceStatus {
+    Ok,
+    // the data is there but doesn't match the piece hash
+    Corrupt,
+    // some of the piece's files are missing or too short
+    Missing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    Ok,
+    Corrupt,
+    Incomplete,
+    Missing,
+}
+
+#[derive(Debug)]
+pub struct FileReport {
+    pub path: PathBuf,
+    pub length: u64,
+    pub exists: bool,
+    // byte ranges within the file covered by pieces that failed
+    pub corrupt: Vec<Range<u64>>,
+    pub missing: Vec<Range<u64>>,
+}
+
+impl FileReport {
+    pub fn status(&self) -> FileStatus {
+        if !self.exists {
+            FileStatus::Missing
+        } else if !self.corrupt.is_empty() {
+            FileStatus::Corrupt
+        } else if !self.missing.is_empty() {
+            FileStatus::Incomplete
+        } else {
+            FileStatus::Ok
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct VerifyReport {
+    pub pieces: Vec<PieceStatus>,
+    pub files: Vec<FileReport>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.pieces.iter().all(|status| *status == PieceStatus::Ok)
+    }
+
+    pub fn failed_pieces(&self) -> Vec<u32> {
+        (0..self.pieces.len() as u32)
+            .filter(|piece| self.pieces[*piece as usize] != PieceStatus::Ok)
+            .collect()
+    }
+}
+
+// Hash-checks the data under `root` against the torrent without modifying anything.
+pub fn verify(root: &Path, torrent: &Torrent) -> Result<VerifyReport, Error> {
+    let layout = StorageLayout::new(torrent);
+    let paths = layout.paths(root)?;
+    let mut files: Vec<Option<(File, u64)>> = paths
+        .iter()
+        .map(|path| {
+            let file = File::open(path).ok()?;
+            let length = file.metadata().ok()?.len();
+            Some((file, length))
+        })
+        .collect();
+
+    let mut pieces = Vec::with_capacity(layout.piece_count);
+    for piece in 0..layout.piece_count as u32 {
+        let size = layout.piece_size(piece);
+        let mut data = vec![0; size as usize];
+        let mut status = PieceStatus::Ok;
+        for span in layout.spans(piece, 0, size as u64)? {
+            let end = span.file_offset + span.range.len() as u64;
+            let Some((file, length)) = files[span.file].as_mut().filter(|(_, len)| *len >= end)
+            else {
+                status = PieceStatus::Missing;
+                break;
+            };
+            file.seek(SeekFrom::Start(span.file_offset))?;
+            // a file that shrank underneath us is missing data, not an error
+            if file.read_exact(&mut data[span.range]).is_err() {
+                *length = 0;
+                status = PieceStatus::Missing;
+                break;
+            }
+        }
+        if status == PieceStatus::Ok
+            && Sha1::digest(&data).as_slice() != torrent.get_piece_hash(piece as usize)
+        {
+            status = PieceStatus::Corrupt;
+        }
+        pieces.push(status);
+    }
+
+    let files = layout
+        .files
+        .iter()
+        .zip(paths)
+        .zip(&files)
+        .map(|((entry, path), file)| {
+            let mut report = FileReport {
+                path,
+                length: entry.length,
+                exists: file.is_some(),
+                corrupt: Vec::new(),
+                missing: Vec::new(),
+            };
+            if entry.length == 0 {
+                return report;
+            }
+            let first = entry.offset / layout.piece_length;
+            let last = (entry.offset + entry.length - 1) / layout.piece_length;
+            for piece in first..=last {
+                let start = piece * layout.piece_length;
+                let end = start + layout.piece_size(piece as u32) as u64;
+                let from = start.max(entry.offset);
+                let to = end.min(entry.offset + entry.length);
+                let ranges = match pieces[piece as usize] {
+                    PieceStatus::Ok => continue,
+                    PieceStatus::Corrupt => &mut report.corrupt,
+                    PieceStatus::Missing => &mut report.missing,
+                };
+                push_range(ranges, from - entry.offset..to - entry.offset);
+            }
+            report
+        })
+        .collect();
+
+    Ok(VerifyReport { pieces, files })
+}
+
+// Appends a range, merging it into the previous one when they touch.
+fn push_range(ranges: &mut Vec<Range<u64>>, range: Range<u64>) {
+    match ranges.last_mut() {
+        Some(last) if last.end == range.start => last.end = range.end,
+        _ => ranges.push(range),
+    }
+}
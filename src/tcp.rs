@@ -1,23 +1,226 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{fmt, net::SocketAddr, sync::Arc, time::Duration};
 
 use anyhow::Error;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
+    time::timeout,
 };
 
-use crate::peer_messages::{MessageId, PeerMessage};
-use crate::{handshake::HandshakeMessage, peer_messages::ExtensionPayload};
+use crate::handshake::HandshakeMessage;
+use crate::listener::{Listener, DEFAULT_PORT};
+use crate::mse::{self, EncryptionPolicy, MseStream};
+use crate::peer_messages::{ExtensionHandshake, ExtensionPayload, MessageId};
+use crate::ratelimit::RateLimits;
+use crate::torrent::{
+    picker::PickStrategy, pipeline::DEFAULT_MAX_REQUESTS, swarm::DEFAULT_MAX_PEERS,
+};
+use crate::utp::UtpSockets;
+
+const ENCRYPTION_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// how long a peer gets to answer our extension handshake, whatever else it sends meanwhile
+const EXTENSION_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// largest message we accept; a piece message carries at most one 16 KiB block
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+const PROTOCOL_HEADER: &[u8; 20] = b"\x13BitTorrent protocol";
+// room made in the read buffer when we don't yet know how long the next message is
+const READ_CHUNK: usize = 16 * 1024;
+
+pub trait PeerStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> PeerStream for T {}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TransportPreference {
+    Tcp,
+    Utp,
+    #[default]
+    PreferTcp,
+    PreferUtp,
+}
+
+impl TransportPreference {
+    // uTP flags in the order they should be tried
+    fn attempts(&self) -> &'static [bool] {
+        match self {
+            TransportPreference::Tcp => &[false],
+            TransportPreference::Utp => &[true],
+            TransportPreference::PreferTcp => &[false, true],
+            TransportPreference::PreferUtp => &[true, false],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectionOptions {
+    pub encryption: EncryptionPolicy,
+    pub transport: TransportPreference,
+    // sockets for outgoing uTP connections, bound on first use
+    pub utp_sockets: UtpSockets,
+    // upper bound on outstanding block requests per peer
+    pub max_requests: usize,
+    // peers connected at once while downloading
+    pub max_peers: usize,
+    pub strategy: PickStrategy,
+    pub rate_limits: RateLimits,
+    // where peers can connect to us; swarms register their torrent with it
+    pub listener: Option<Arc<Listener>>,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        Self {
+            encryption: EncryptionPolicy::default(),
+            transport: TransportPreference::default(),
+            utp_sockets: UtpSockets::default(),
+            max_requests: DEFAULT_MAX_REQUESTS,
+            max_peers: DEFAULT_MAX_PEERS,
+            strategy: PickStrategy::default(),
+            rate_limits: RateLimits::default(),
+            listener: None,
+        }
+    }
+}
+
+impl ConnectionOptions {
+    // The port we tell trackers about.
+    pub fn port(&self) -> u16 {
+        self.listener
+            .as_ref()
+            .map_or(DEFAULT_PORT, |listener| listener.local_addr().port())
+    }
+}
 
-#[derive(Debug)]
 pub struct TcpManager {
-    stream: TcpStream,
+    stream: Box<dyn PeerStream>,
+    read_buf: BytesMut,
+    // reused for every outgoing message
+    write_buf: BytesMut,
+    encrypted: bool,
+    utp: bool,
+}
+
+impl fmt::Debug for TcpManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TcpManager")
+            .field("encrypted", &self.encrypted)
+            .field("utp", &self.utp)
+            .finish()
+    }
 }
 
 impl TcpManager {
-    pub async fn connect(peer: SocketAddr) -> Self {
-        let stream = TcpStream::connect(peer).await.unwrap();
-        Self { stream }
+    pub async fn connect(peer: SocketAddr) -> Result<Self, Error> {
+        let stream = connect_tcp(peer).await?;
+        Ok(Self::from_stream(stream, false))
+    }
+
+    // Connects over the preferred transport, falling back to the other one when allowed, then
+    // applies the encryption policy. With `Prefer` a failed encrypted handshake falls back
+    // to a fresh plaintext connection over the same transport.
+    pub async fn connect_with(
+        peer: SocketAddr,
+        info_hash: [u8; 20],
+        options: &ConnectionOptions,
+    ) -> Result<Self, Error> {
+        let (stream, utp) = open_transport(peer, options).await?;
+        let mut manager = Self::encrypt(stream, peer, utp, info_hash, options).await?;
+        manager.utp = utp;
+        Ok(manager)
+    }
+
+    async fn encrypt(
+        stream: Box<dyn PeerStream>,
+        peer: SocketAddr,
+        utp: bool,
+        info_hash: [u8; 20],
+        options: &ConnectionOptions,
+    ) -> Result<Self, Error> {
+        let policy = options.encryption;
+        if policy == EncryptionPolicy::Disabled {
+            return Ok(Self::from_stream(stream, false));
+        }
+
+        let result = timeout(
+            ENCRYPTION_HANDSHAKE_TIMEOUT,
+            mse::initiate(stream, info_hash, policy),
+        )
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("Encrypted handshake timed out")));
+
+        match result {
+            Ok(stream) => {
+                let encrypted = stream.is_encrypted();
+                Ok(Self::from_stream(stream, encrypted))
+            }
+            Err(e) if policy == EncryptionPolicy::Require => Err(anyhow::anyhow!(
+                "Encrypted handshake with {} failed: {}",
+                peer,
+                e
+            )),
+            Err(_) => {
+                let stream = open_stream(peer, utp, options).await?;
+                Ok(Self::from_stream(stream, false))
+            }
+        }
+    }
+
+    // Accepts an incoming connection, detecting whether it opens with a plaintext or an
+    // encrypted handshake. Returns the info hash when the encrypted handshake revealed it.
+    pub async fn accept(
+        mut stream: impl PeerStream + 'static,
+        info_hashes: &[[u8; 20]],
+        policy: EncryptionPolicy,
+    ) -> Result<(Self, Option<[u8; 20]>), Error> {
+        let mut header = [0u8; 20];
+        stream.read_exact(&mut header).await?;
+
+        if &header == PROTOCOL_HEADER {
+            if policy == EncryptionPolicy::Require {
+                return Err(anyhow::anyhow!("Rejecting plaintext connection"));
+            }
+            let stream = MseStream::plaintext(stream, header.to_vec());
+            return Ok((Self::from_stream(stream, false), None));
+        }
+
+        if policy == EncryptionPolicy::Disabled {
+            return Err(anyhow::anyhow!("Rejecting encrypted connection"));
+        }
+        let (stream, info_hash) = timeout(
+            ENCRYPTION_HANDSHAKE_TIMEOUT,
+            mse::accept(stream, &header, info_hashes, policy),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("Encrypted handshake timed out"))??;
+        let encrypted = stream.is_encrypted();
+        Ok((Self::from_stream(stream, encrypted), Some(info_hash)))
+    }
+
+    pub fn from_stream(stream: impl PeerStream + 'static, encrypted: bool) -> Self {
+        Self {
+            stream: Box::new(stream),
+            read_buf: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            encrypted,
+            utp: false,
+        }
+    }
+
+    // Puts an accepted connection under `limits`; outgoing ones are limited when they're opened.
+    pub fn rate_limited(self, peer: SocketAddr, limits: &RateLimits) -> Self {
+        Self {
+            stream: Box::new(limits.limit(peer, self.stream)),
+            ..self
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    pub fn is_utp(&self) -> bool {
+        self.utp
     }
 
     pub async fn disconnect(&mut self) {
@@ -28,12 +231,24 @@ impl TcpManager {
         &mut self,
         handshake_message: HandshakeMessage,
     ) -> Result<HandshakeMessage, Error> {
+        self.send_handshake(&handshake_message).await?;
+        self.read_handshake().await
+    }
+
+    pub async fn send_handshake(
+        &mut self,
+        handshake_message: &HandshakeMessage,
+    ) -> Result<(), Error> {
         let handshake_message_bytes = handshake_message.to_bytes();
         self.stream
             .write_all(&handshake_message_bytes)
             .await
             .map_err(|e| anyhow::anyhow!("Failed to send handshake message: {}", e))?;
+        self.stream.flush().await?;
+        Ok(())
+    }
 
+    pub async fn read_handshake(&mut self) -> Result<HandshakeMessage, Error> {
         let mut buffer = [0; 68];
         self.stream
             .read_exact(&mut buffer)
@@ -44,60 +259,124 @@ impl TcpManager {
         Ok(resp)
     }
 
-    pub async fn extension_handshake(&mut self) -> Result<ExtensionPayload, Error> {
-        let mut msg_bytes = vec![0u8];
-        let msg = HashMap::from([(
-            "m".to_string(),
-            HashMap::from([("ut_metadata".to_string(), 21)]),
-        )]);
-        msg_bytes.extend(serde_bencode::to_bytes(&msg).unwrap());
-
+    pub async fn extension_handshake(
+        &mut self,
+        metadata_size: Option<usize>,
+    ) -> Result<ExtensionPayload, Error> {
         // sending extension handshake message
-        self.send_message(MessageId::Extension, msg_bytes).await?;
+        self.send_extension_handshake(ExtensionHandshake::new(metadata_size, true))
+            .await?;
 
-        // reading extension handshake response
-        let (_msg_id, payload) = self.read_message().await?;
-        println!("message Id: {:?}", _msg_id);
-        let extension_payload = ExtensionPayload::from_bytes(&payload);
-        Ok(extension_payload)
+        // reading extension handshake response, skipping bitfield/have/unchoke on the way
+        timeout(EXTENSION_HANDSHAKE_TIMEOUT, async {
+            loop {
+                let (msg_id, payload) = self.read_message().await?;
+                if msg_id == MessageId::Extension && payload.first() == Some(&0) {
+                    return ExtensionPayload::from_bytes(&payload);
+                }
+            }
+        })
+        .await
+        .map_err(|_| anyhow::anyhow!("Extension handshake timed out"))?
     }
 
-    pub async fn read_message(&mut self) -> Result<(MessageId, Vec<u8>), Error> {
-        let mut length_buffer = [0u8; 4];
-        self.stream
-            .read_exact(&mut length_buffer)
-            .await
-            .expect("Failed to read message length");
-        let length = u32::from_be_bytes(length_buffer);
+    pub async fn send_extension_handshake(
+        &mut self,
+        handshake: ExtensionHandshake,
+    ) -> Result<(), Error> {
+        let msg_bytes = handshake.to_bytes();
+        self.send_message(MessageId::Extension, msg_bytes).await
+    }
 
-        if length == 0 {
-            return Err(anyhow::anyhow!("received empty message (length=0)"));
-        }
+    // Cancel safe: bytes are buffered until a whole message has arrived, so the future can
+    // be dropped (e.g. by a timeout) without losing stream position. The payload is a view
+    // into the read buffer, not a copy.
+    pub async fn read_message(&mut self) -> Result<(MessageId, Bytes), Error> {
+        loop {
+            // a whole message lands in one allocation, so it can be handed out without copying
+            let mut wanted = READ_CHUNK;
+            if self.read_buf.len() >= 4 {
+                let length = u32::from_be_bytes(self.read_buf[..4].try_into().unwrap()) as usize;
 
-        let mut message_buffer = vec![0u8; length as usize];
-        self.stream
-            .read_exact(&mut message_buffer)
-            .await
-            .expect("Failed to read message content");
+                if length == 0 {
+                    // keep-alive
+                    self.read_buf.advance(4);
+                    continue;
+                }
+                if length > MAX_MESSAGE_LEN {
+                    return Err(anyhow::anyhow!("message too long ({} bytes)", length));
+                }
 
-        let message_id = MessageId::from(message_buffer[0]);
-        let payload = message_buffer[1..].to_vec();
+                if self.read_buf.len() >= 4 + length {
+                    let mut message = self.read_buf.split_to(4 + length).freeze();
+                    let message_id = MessageId::from(message[4]);
+                    return Ok((message_id, message.split_off(5)));
+                }
+                wanted = 4 + length - self.read_buf.len();
+            }
+            self.read_buf.reserve(wanted);
 
-        Ok((message_id, payload))
+            let n = self
+                .stream
+                .read_buf(&mut self.read_buf)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to read message: {}", e))?;
+            if n == 0 {
+                return Err(anyhow::anyhow!("Failed to read message: connection closed"));
+            }
+        }
     }
 
     pub async fn send_message(
         &mut self,
         message_id: MessageId,
-        payload: Vec<u8>,
+        payload: impl AsRef<[u8]>,
     ) -> Result<(), Error> {
-        let message = PeerMessage::new(message_id, payload);
-        let message_bytes = message.to_bytes();
+        let payload = payload.as_ref();
+        self.write_buf.clear();
+        self.write_buf.reserve(5 + payload.len());
+        self.write_buf.put_u32(1 + payload.len() as u32);
+        self.write_buf.put_u8(message_id as u8);
+        self.write_buf.put_slice(payload);
         self.stream
-            .write_all(&message_bytes)
+            .write_all(&self.write_buf)
             .await
             .map_err(|e| anyhow::anyhow!("Failed to send message: {}", e))?;
+        self.stream.flush().await?;
 
         Ok(())
     }
 }
+
+async fn open_transport(
+    peer: SocketAddr,
+    options: &ConnectionOptions,
+) -> Result<(Box<dyn PeerStream>, bool), Error> {
+    let mut last_error = None;
+    for &utp in options.transport.attempts() {
+        match open_stream(peer, utp, options).await {
+            Ok(stream) => return Ok((stream, utp)),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap())
+}
+
+async fn open_stream(
+    peer: SocketAddr,
+    utp: bool,
+    options: &ConnectionOptions,
+) -> Result<Box<dyn PeerStream>, Error> {
+    let limits = &options.rate_limits;
+    if !utp {
+        return Ok(Box::new(limits.limit(peer, connect_tcp(peer).await?)));
+    }
+    let socket = options.utp_sockets.for_peer(peer).await?;
+    Ok(Box::new(limits.limit(peer, socket.connect(peer).await?)))
+}
+
+async fn connect_tcp(peer: SocketAddr) -> Result<TcpStream, Error> {
+    TcpStream::connect(peer)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to connect to peer {}: {}", peer, e))
+}
@@ -0,0 +1,89 @@
+use anyhow::Error;
+
+// One bit per piece, high bit of the first byte is piece 0, as sent in a Bitfield message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Self::new(len);
+        for i in 0..len {
+            bitfield.set(i);
+        }
+        bitfield
+    }
+
+    // Validates a received bitfield's length. Spare bits past the last piece are cleared
+    // rather than rejected, since some clients don't zero them.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Result<Self, Error> {
+        if bytes.len() != len.div_ceil(8) {
+            return Err(anyhow::anyhow!(
+                "Bitfield has {} bytes, expected {}",
+                bytes.len(),
+                len.div_ceil(8)
+            ));
+        }
+        let mut bitfield = Self {
+            bytes: bytes.to_vec(),
+            len,
+        };
+        if !len.is_multiple_of(8) {
+            *bitfield.bytes.last_mut().unwrap() &= 0xff << (8 - len % 8);
+        }
+        Ok(bitfield)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        index < self.len && self.bit(index)
+    }
+
+    pub fn set(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    pub fn clear(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.bytes.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|i| self.bit(*i))
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+}
@@ -1,137 +1,728 @@
-use std::{cmp::min, net::SocketAddr};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use anyhow::Error;
-use sha1::{Digest, Sha1};
+use bytes::Bytes;
+use tokio::sync::mpsc::Sender;
 
 use crate::handshake::HandshakeMessage;
+use crate::magnet::metadata::{self, MetadataMessage, MetadataMessageType, UT_METADATA_ID};
+use crate::peer_messages::{
+    ExtensionHandshake, ExtensionPayload, MessageId, PiecePayload, RequestPayload,
+};
+use crate::pex::{self, PeerPool, PexMessage, PexState, UT_PEX_ID};
+use crate::storage::Storage;
+use crate::tcp::{ConnectionOptions, TcpManager};
+use crate::torrent::bitfield::Bitfield;
+use crate::torrent::events::{Event, Events};
+use crate::torrent::peer::PeerState;
+use crate::torrent::picker::PiecePicker;
+use crate::torrent::pipeline::{BlockRequest, RequestPipeline};
+use crate::torrent::smartban::SmartBan;
 use crate::torrent::torrent::Torrent;
-use crate::peer_messages::{MessageId, PiecePayload, RequestPayload};
-use crate::tcp::TcpManager;
+
+// how long we wait for a choking peer to unchoke us before giving up on it
+const CHOKE_TIMEOUT: Duration = Duration::from_secs(60);
+// how long an idle peer waits for news (have, unchoke) before asking the picker again
+const IDLE_INTERVAL: Duration = Duration::from_secs(1);
+// how long we keep a peer that has nothing we want
+const UNINTERESTING_TIMEOUT: Duration = Duration::from_secs(30);
+// largest block we serve; anything bigger is a misbehaving peer
+const MAX_SERVED_BLOCK: u32 = 1 << 17;
+
 pub struct Client {
     torrent: Torrent,
+    metadata: Vec<u8>,
+    options: ConnectionOptions,
     stream: Option<TcpManager>,
+    peer: Option<SocketAddr>,
+    // whether the peer connected to us, so `peer` has a port it doesn't listen on
+    incoming: bool,
+    // the address the peer is recorded under in the peer pool
+    pooled: Option<SocketAddr>,
+    peer_extensions: Option<ExtensionPayload>,
+    peer_pool: Option<Arc<Mutex<PeerPool>>>,
+    pex: PexState,
+    pipeline: RequestPipeline,
+    state: PeerState,
+    picker: Option<Arc<Mutex<PiecePicker>>>,
+    storage: Option<Arc<Mutex<dyn Storage>>>,
+    // pieces we've told the peer we have
+    announced: Bitfield,
+    events: Events,
+    smart_ban: Option<Arc<Mutex<SmartBan>>>,
 }
 
 impl Client {
     pub fn new(torrent: Torrent) -> Self {
+        let metadata = torrent.get_info_bytes();
+        let options = ConnectionOptions::default();
         Self {
+            state: PeerState::new(torrent.get_piece_count()),
+            announced: Bitfield::new(torrent.get_piece_count()),
             torrent,
+            metadata,
             stream: None,
+            peer: None,
+            incoming: false,
+            pooled: None,
+            peer_extensions: None,
+            peer_pool: None,
+            pex: PexState::default(),
+            picker: None,
+            storage: None,
+            events: Events::default(),
+            smart_ban: None,
+            pipeline: RequestPipeline::new(options.max_requests),
+            options,
         }
     }
 
+    pub fn set_connection_options(&mut self, options: ConnectionOptions) {
+        self.pipeline = RequestPipeline::new(options.max_requests);
+        self.options = options;
+    }
+
+    pub fn set_peer_pool(&mut self, peer_pool: Arc<Mutex<PeerPool>>) {
+        self.peer_pool = Some(peer_pool);
+    }
+
+    pub fn set_picker(&mut self, picker: Arc<Mutex<PiecePicker>>) {
+        self.picker = Some(picker);
+    }
+
+    pub fn set_storage(&mut self, storage: Arc<Mutex<dyn Storage>>) {
+        self.storage = Some(storage);
+    }
+
+    pub fn set_events(&mut self, events: Events) {
+        self.events = events;
+    }
+
+    pub fn set_smart_ban(&mut self, smart_ban: Arc<Mutex<SmartBan>>) {
+        self.smart_ban = Some(smart_ban);
+    }
+
     pub fn set_stream(&mut self, stream: TcpManager) {
         self.stream = Some(stream);
     }
 
     pub async fn handshake(&mut self, peer: SocketAddr) -> Result<(), Error> {
-        let stream = TcpManager::connect(peer).await;
-        self.stream = Some(stream);
-
-        let handshake_message = HandshakeMessage::new(self.torrent.get_info_hash(), false);
-        let _ = self
-            .stream
-            .as_mut()
-            .unwrap()
+        let mut stream =
+            TcpManager::connect_with(peer, self.torrent.get_info_hash(), &self.options).await?;
+        let handshake_message = HandshakeMessage::new(self.torrent.get_info_hash(), true);
+        let handshake_resp = stream
             .handshake(handshake_message)
             .await
             .map_err(|e| anyhow::anyhow!("Failed to handshake: {}", e))?;
+        self.incoming = false;
+        self.start(peer, stream, handshake_resp).await
+    }
+
+    // Takes over a connection from a peer that connected to us, answering the handshake it
+    // already sent.
+    pub async fn accept(
+        &mut self,
+        peer: SocketAddr,
+        stream: TcpManager,
+        handshake: HandshakeMessage,
+    ) -> Result<(), Error> {
+        let mut stream = stream.rate_limited(peer, &self.options.rate_limits);
+        let handshake_message = HandshakeMessage::new(self.torrent.get_info_hash(), true);
+        stream
+            .send_handshake(&handshake_message)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to handshake: {}", e))?;
+        self.incoming = true;
+        self.start(peer, stream, handshake).await
+    }
+
+    // Sets up a fresh connection once handshakes have been exchanged.
+    async fn start(
+        &mut self,
+        peer: SocketAddr,
+        stream: TcpManager,
+        handshake_resp: HandshakeMessage,
+    ) -> Result<(), Error> {
+        self.pipeline = RequestPipeline::new(self.options.max_requests);
+        if let Some(picker) = &self.picker {
+            picker.lock().unwrap().remove_peer(&self.state.bitfield);
+        }
+        if let (Some(peer_pool), Some(pooled)) = (&self.peer_pool, self.pooled.take()) {
+            peer_pool.lock().unwrap().mark_disconnected(pooled);
+        }
+        self.state = PeerState::new(self.torrent.get_piece_count());
+        self.stream = Some(stream);
+        self.peer = Some(peer);
+        self.peer_extensions = None;
+
+        if handshake_resp.supports_extensions() {
+            // advertise the metadata so magnet peers can fetch it from us
+            self.stream
+                .as_mut()
+                .unwrap()
+                .send_extension_handshake(ExtensionHandshake::new(
+                    Some(self.metadata.len()),
+                    !self.torrent.is_private(),
+                ))
+                .await?;
+        }
+
+        self.announced = Bitfield::new(self.torrent.get_piece_count());
+        self.send_bitfield().await?;
+        self.update_peer_pool();
+
+        Ok(())
+    }
 
-        // Read bitfield message
-        let (message_id, _) = self
-            .stream
+    async fn send_bitfield(&mut self) -> Result<(), Error> {
+        let (Some(picker), Some(_)) = (&self.picker, &self.storage) else {
+            return Ok(());
+        };
+        let have = picker.lock().unwrap().have().clone();
+        if have.count() == 0 {
+            return Ok(());
+        }
+        self.stream
             .as_mut()
             .unwrap()
-            .read_message()
-            .await
-            .map_err(|e| anyhow::anyhow!("Failed to read bitfield message: {}", e))?;
+            .send_message(MessageId::Bitfield, have.to_bytes())
+            .await?;
+        self.announced = have;
+        Ok(())
+    }
+
+    // Tells the peer about pieces we've verified since we last told it.
+    async fn send_haves(&mut self) -> Result<(), Error> {
+        let (Some(picker), Some(_)) = (&self.picker, &self.storage) else {
+            return Ok(());
+        };
+        let new: Vec<usize> = {
+            let picker = picker.lock().unwrap();
+            picker
+                .have()
+                .iter()
+                .filter(|piece| !self.announced.has(*piece))
+                .collect()
+        };
+        for piece in new {
+            self.stream
+                .as_mut()
+                .unwrap()
+                .send_message(MessageId::Have, (piece as u32).to_be_bytes().to_vec())
+                .await?;
+            self.announced.set(piece);
+        }
+        Ok(())
+    }
+
+    pub fn state(&self) -> &PeerState {
+        &self.state
+    }
+
+    // Records the peer in the shared pool with the flags we'd advertise for it over ut_pex. A
+    // peer that connected to us is left out until its extension handshake names the port it
+    // listens on, and even then we don't know that port is reachable.
+    fn update_peer_pool(&mut self) {
+        let (Some(peer_pool), Some(peer), Some(stream)) =
+            (&self.peer_pool, self.peer, &self.stream)
+        else {
+            return;
+        };
+        let (addr, mut flags) = if self.incoming {
+            let port = self
+                .peer_extensions
+                .as_ref()
+                .and_then(|extensions| extensions.get_port());
+            let Some(port) = port else {
+                return;
+            };
+            (SocketAddr::new(peer.ip(), port), 0)
+        } else {
+            (peer, pex::FLAG_REACHABLE)
+        };
+        if self.state.is_seed() {
+            flags |= pex::FLAG_SEED;
+        }
+        if stream.is_encrypted() {
+            flags |= pex::FLAG_ENCRYPTION;
+        }
+        if stream.is_utp() {
+            flags |= pex::FLAG_UTP;
+        }
+        let mut peer_pool = peer_pool.lock().unwrap();
+        if let Some(pooled) = self.pooled.filter(|pooled| *pooled != addr) {
+            peer_pool.mark_disconnected(pooled);
+        }
+        peer_pool.mark_connected(addr, flags);
+        self.pooled = Some(addr);
+    }
 
-        if message_id != MessageId::Bitfield {
-            return Err(anyhow::anyhow!(
-                "Expected bitfield message, got {:?}",
-                message_id
-            ));
+    // Sends the peer the changes to our connected set, at most once per PEX interval.
+    pub async fn send_pex(&mut self) -> Result<(), Error> {
+        if self.torrent.is_private() {
+            return Ok(());
         }
+        let (Some(peer_pool), Some(extension_id)) = (
+            self.peer_pool.as_ref(),
+            self.peer_extensions
+                .as_ref()
+                .and_then(|extensions| extensions.get_extension("ut_pex")),
+        ) else {
+            return Ok(());
+        };
 
+        let message = {
+            let peer_pool = peer_pool.lock().unwrap();
+            self.pex
+                .next_message(peer_pool.connected_peers(), self.pooled)
+        };
+        if let Some(message) = message {
+            self.stream
+                .as_mut()
+                .unwrap()
+                .send_message(MessageId::Extension, message.to_bytes(extension_id as u8))
+                .await?;
+        }
         Ok(())
     }
 
     pub async fn init_download(&mut self) -> Result<(), Error> {
-        // Send interested message
-        let _ = self
-            .stream
+        self.send_interested().await?;
+        while self.state.peer_choking {
+            let (message_id, payload) = self.receive(CHOKE_TIMEOUT).await?;
+            self.handle_message(message_id, payload).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_interested(&mut self) -> Result<(), Error> {
+        self.stream
             .as_mut()
             .unwrap()
             .send_message(MessageId::Interested, vec![])
             .await
             .map_err(|e| anyhow::anyhow!("Failed to send interested message: {}", e))?;
+        self.state.am_interested = true;
+        Ok(())
+    }
 
-        // Read unchoke message
-        let (message_id, _) = self
-            .stream
-            .as_mut()
-            .unwrap()
-            .read_message()
-            .await
-            .map_err(|e| anyhow::anyhow!("Failed to read unchoke message: {}", e))?;
-
-        if message_id != MessageId::Unchoke {
-            return Err(anyhow::anyhow!(
-                "Expected unchoke message, got {:?}",
-                message_id
-            ));
+    // Downloads blocks handed out by the shared picker into storage until the peer has nothing
+    // left that we want. The index of each verified piece is sent on `tx`.
+    pub async fn download(&mut self, tx: &Sender<u32>) -> Result<(), Error> {
+        let (Some(picker), Some(storage)) = (self.picker.clone(), self.storage.clone()) else {
+            return Err(anyhow::anyhow!("No piece picker or storage set"));
+        };
+        if self.stream.is_none() {
+            return Err(anyhow::anyhow!("Stream is not initialized"));
+        }
+        if !self.state.am_interested {
+            self.send_interested().await?;
         }
 
-        Ok(())
+        let mut received = picker.lock().unwrap().subscribe();
+        let mut interesting_at = Instant::now();
+        loop {
+            if self.is_banned() {
+                return Err(anyhow::anyhow!("Banned for sending corrupt data"));
+            }
+            let (finished, interesting) = {
+                let picker = picker.lock().unwrap();
+                (
+                    picker.is_finished(),
+                    picker.is_interesting(&self.state.bitfield),
+                )
+            };
+            if interesting {
+                interesting_at = Instant::now();
+            }
+            // the bitfield (or a have) may still be on its way, so give the peer some time
+            let useless = !interesting && interesting_at.elapsed() >= UNINTERESTING_TIMEOUT;
+            if (finished || useless) && self.pipeline.is_empty() {
+                return Ok(());
+            }
+
+            if !self.state.peer_choking {
+                while self.pipeline.can_request() {
+                    let request = picker
+                        .lock()
+                        .unwrap()
+                        .next_request(&self.state.bitfield, &self.pipeline);
+                    let Some(request) = request else {
+                        break;
+                    };
+                    self.send_request(request).await?;
+                }
+            }
+
+            let wait = if self.state.peer_choking {
+                CHOKE_TIMEOUT
+            } else if self.pipeline.is_empty() {
+                // everything this peer has is taken by other peers; wait in case one fails
+                IDLE_INTERVAL
+            } else {
+                self.pipeline.next_timeout()
+            };
+            let read = tokio::time::timeout(wait, self.stream.as_mut().unwrap().read_message());
+            let message = tokio::select! {
+                message = read => Some(message),
+                // in endgame another peer may deliver a block we're still waiting for
+                _ = received.changed() => None,
+            };
+            let Some(message) = message else {
+                self.cancel_received(&picker).await?;
+                self.send_haves().await?;
+                self.send_pex().await?;
+                continue;
+            };
+            let Ok(message) = message else {
+                if self.state.peer_choking {
+                    return Err(anyhow::anyhow!("Peer kept us choked"));
+                }
+                // stale requests go back to the picker so any peer can ask for them again
+                let expired = self.pipeline.expire();
+                self.release(&expired);
+                self.send_pex().await?;
+                continue;
+            };
+
+            let (message_id, payload) = message?;
+            if let Some(block) = self.handle_message(message_id, payload).await? {
+                self.events.add_downloaded(block.block.len());
+                self.pipeline
+                    .on_block(block.index, block.begin, block.block.len());
+                // a block that was dropped by a choke may still turn up afterwards; the
+                // picker takes any block it's still missing. It's written before the picker
+                // is let go, so a resume record never lists a block that isn't in storage.
+                let accepted = {
+                    let mut picker = picker.lock().unwrap();
+                    let accepted = picker.on_block(block.index, block.begin, &block.block);
+                    if accepted.is_some() {
+                        storage.lock().unwrap().write_block(
+                            block.index,
+                            block.begin,
+                            &block.block,
+                        )?;
+                    }
+                    accepted
+                };
+                if let Some(complete) = accepted {
+                    if let (Some(smart_ban), Some(peer)) = (&self.smart_ban, self.peer) {
+                        smart_ban.lock().unwrap().on_block(
+                            block.index,
+                            block.begin,
+                            block.block.len(),
+                            peer.ip(),
+                        );
+                    }
+                    if complete {
+                        self.complete_piece(&picker, &storage, block.index, tx)
+                            .await?;
+                    }
+                }
+            }
+
+            if self.state.peer_choking && !self.pipeline.is_empty() {
+                // the peer discards our requests when it chokes us
+                let dropped = self.pipeline.clear();
+                self.release(&dropped);
+            }
+        }
     }
 
-    pub async fn download_piece(&mut self, piece_index: u32) -> Result<Vec<u8>, Error> {
+    // Answers the peer's requests, for when there's nothing left to download from it. Returns
+    // once the peer has lost interest in us for a while.
+    pub async fn serve(&mut self) -> Result<(), Error> {
         if self.stream.is_none() {
             return Err(anyhow::anyhow!("Stream is not initialized"));
         }
+        let mut interested_at = Instant::now();
+        loop {
+            if self.state.peer_interested {
+                interested_at = Instant::now();
+            } else if interested_at.elapsed() >= UNINTERESTING_TIMEOUT {
+                return Ok(());
+            }
+            self.send_haves().await?;
+            self.send_pex().await?;
 
-        let stream = self.stream.as_mut().unwrap();
-        let piece_length = self.torrent.get_piece_length(piece_index as usize);
-        let reading_len = 1 << 14;
-
-        let mut data = Vec::new();
-        let mut begin = 0u32;
-        let mut length = min(reading_len as u32, piece_length - begin);
+            let read =
+                tokio::time::timeout(IDLE_INTERVAL, self.stream.as_mut().unwrap().read_message());
+            let Ok(message) = read.await else {
+                continue;
+            };
+            let (message_id, payload) = message?;
+            // we aren't asking for anything, so any piece that turns up is dropped
+            self.handle_message(message_id, payload).await?;
+        }
+    }
 
-        while begin < piece_length {
-            let request_message = RequestPayload::new(piece_index, begin, length);
-            let _ = stream
-                .send_message(MessageId::Request, request_message.to_bytes())
+    // Cancels our requests for blocks that another peer has already delivered.
+    async fn cancel_received(&mut self, picker: &Mutex<PiecePicker>) -> Result<(), Error> {
+        let received: Vec<BlockRequest> = {
+            let picker = picker.lock().unwrap();
+            self.pipeline
+                .requests()
+                .into_iter()
+                .filter(|request| picker.is_received(request))
+                .collect()
+        };
+        for request in received {
+            self.pipeline.remove(request.index, request.begin);
+            let cancel_message = RequestPayload::new(request.index, request.begin, request.length);
+            self.stream
+                .as_mut()
+                .unwrap()
+                .send_message(MessageId::Cancel, cancel_message.to_bytes())
                 .await
-                .map_err(|e| anyhow::anyhow!("Failed to send request message: {}", e))?;
+                .map_err(|e| anyhow::anyhow!("Failed to send cancel message: {}", e))?;
+        }
+        Ok(())
+    }
 
-            let (message_id, payload) = stream.read_message().await?;
-            if message_id != MessageId::Piece {
-                return Err(anyhow::anyhow!(
-                    "Expected piece message, got {:?}",
-                    message_id
-                ));
+    async fn complete_piece(
+        &mut self,
+        picker: &Mutex<PiecePicker>,
+        storage: &Mutex<dyn Storage>,
+        piece_index: u32,
+        tx: &Sender<u32>,
+    ) -> Result<(), Error> {
+        let piece_hash = self.torrent.get_piece_hash(piece_index as usize);
+        let (hasher, hashed) = picker.lock().unwrap().take_hash(piece_index);
+        let ok = storage
+            .lock()
+            .unwrap()
+            .verify_rest(piece_index, hasher, hashed, &piece_hash)?;
+        if !ok {
+            if let Some(peer) = self.peer {
+                self.events.send(Event::HashFailed {
+                    piece: piece_index,
+                    peer,
+                });
             }
+            if let Some(smart_ban) = &self.smart_ban {
+                let data = storage.lock().unwrap().read_piece(piece_index)?;
+                let banned = smart_ban.lock().unwrap().piece_failed(piece_index, &data);
+                self.ban(&banned);
+            }
+            // back to the picker, so any peer (including this one) can fetch it again
+            picker.lock().unwrap().piece_failed(piece_index);
+            return Ok(());
+        }
+        picker.lock().unwrap().piece_verified(piece_index);
+        if let Some(smart_ban) = &self.smart_ban {
+            let culprits = smart_ban.lock().unwrap().piece_verified(piece_index, || {
+                storage.lock().unwrap().read_piece(piece_index)
+            })?;
+            self.ban(&culprits);
+        }
+        let _ = tx.send(piece_index).await;
 
-            let piece_payload = PiecePayload::from_bytes(&payload);
-            data.extend_from_slice(&piece_payload.block);
+        self.send_haves().await?;
+        self.send_pex().await
+    }
 
-            begin += length;
-            length = min(reading_len as u32, piece_length - begin);
+    fn ban(&self, ips: &[IpAddr]) {
+        let Some(peer_pool) = &self.peer_pool else {
+            return;
+        };
+        for ip in ips {
+            if peer_pool.lock().unwrap().ban(*ip) {
+                self.events.send(Event::PeerBanned(*ip));
+            }
         }
+    }
 
-        if !self.cmp_hash(piece_index, data.clone()) {
-            return Err(anyhow::anyhow!("corrupted piece downloaded"));
+    fn is_banned(&self) -> bool {
+        match (&self.peer_pool, self.peer) {
+            (Some(peer_pool), Some(peer)) => peer_pool.lock().unwrap().is_banned(peer.ip()),
+            _ => false,
         }
+    }
 
-        Ok(data)
+    fn release(&self, requests: &[BlockRequest]) {
+        if let Some(picker) = &self.picker {
+            let mut picker = picker.lock().unwrap();
+            for request in requests {
+                picker.release(request);
+            }
+        }
     }
 
-    fn cmp_hash(&self, piece_index: u32, data: Vec<u8>) -> bool {
-        let piece_hash = self.torrent.get_piece_hash(piece_index as usize);
-        let mut hasher = Sha1::new();
-        hasher.update(data);
-        let hash = hasher.finalize().to_vec();
-        hash == piece_hash
+    async fn send_request(&mut self, request: BlockRequest) -> Result<(), Error> {
+        // tracked before sending so the block is released to the picker if the send fails
+        self.pipeline.on_request(request);
+        let request_message = RequestPayload::new(request.index, request.begin, request.length);
+        self.stream
+            .as_mut()
+            .unwrap()
+            .send_message(MessageId::Request, request_message.to_bytes())
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to send request message: {}", e))?;
+        Ok(())
+    }
+
+    async fn receive(&mut self, wait: Duration) -> Result<(MessageId, Bytes), Error> {
+        tokio::time::timeout(wait, self.stream.as_mut().unwrap().read_message())
+            .await
+            .map_err(|_| anyhow::anyhow!("Timed out waiting for peer message"))?
+    }
+
+    // Applies any message to the peer state. Piece messages are handed back to the caller.
+    async fn handle_message(
+        &mut self,
+        message_id: MessageId,
+        payload: Bytes,
+    ) -> Result<Option<PiecePayload>, Error> {
+        match message_id {
+            MessageId::Choke => self.state.peer_choking = true,
+            MessageId::Unchoke => self.state.peer_choking = false,
+            MessageId::Interested => {
+                self.state.peer_interested = true;
+                if self.storage.is_some() && self.state.am_choking {
+                    self.stream
+                        .as_mut()
+                        .unwrap()
+                        .send_message(MessageId::Unchoke, vec![])
+                        .await?;
+                    self.state.am_choking = false;
+                }
+            }
+            MessageId::NotInterested => self.state.peer_interested = false,
+            MessageId::Have => {
+                let index: [u8; 4] = payload
+                    .get(..4)
+                    .and_then(|index| index.try_into().ok())
+                    .ok_or_else(|| anyhow::anyhow!("Invalid have message"))?;
+                let index = u32::from_be_bytes(index);
+                if self.state.has_piece(index as usize) {
+                    return Ok(None);
+                }
+                self.state.bitfield.set(index as usize);
+                if let Some(picker) = &self.picker {
+                    picker.lock().unwrap().peer_has(index);
+                }
+                if self.state.is_seed() {
+                    self.update_peer_pool();
+                }
+            }
+            MessageId::Bitfield => {
+                let bitfield = Bitfield::from_bytes(&payload, self.torrent.get_piece_count())?;
+                if let Some(picker) = &self.picker {
+                    let mut picker = picker.lock().unwrap();
+                    picker.remove_peer(&self.state.bitfield);
+                    picker.add_peer(&bitfield);
+                }
+                self.state.bitfield = bitfield;
+                self.update_peer_pool();
+            }
+            MessageId::Piece => {
+                if payload.len() < 8 {
+                    return Err(anyhow::anyhow!("Invalid piece message"));
+                }
+                return Ok(Some(PiecePayload::from_bytes(payload)));
+            }
+            MessageId::Extension => self.handle_extension_message(&payload).await?,
+            MessageId::Request => self.serve_request(&payload).await?,
+            // requests are answered as soon as they arrive, so there's nothing to cancel
+            MessageId::Cancel | MessageId::Port | MessageId::Unknown => {}
+        }
+        Ok(None)
+    }
+
+    async fn serve_request(&mut self, payload: &[u8]) -> Result<(), Error> {
+        let (Some(picker), Some(storage)) = (&self.picker, &self.storage) else {
+            return Ok(());
+        };
+        let Some(request) = RequestPayload::from_bytes(payload) else {
+            return Err(anyhow::anyhow!("Invalid request message"));
+        };
+        if self.state.am_choking
+            || request.length > MAX_SERVED_BLOCK
+            || !picker.lock().unwrap().have().has(request.index as usize)
+        {
+            return Ok(());
+        }
+
+        let block =
+            storage
+                .lock()
+                .unwrap()
+                .read_block(request.index, request.begin, request.length)?;
+        let length = block.len();
+        let piece = PiecePayload {
+            index: request.index,
+            begin: request.begin,
+            block: block.into(),
+        };
+        self.stream
+            .as_mut()
+            .unwrap()
+            .send_message(MessageId::Piece, piece.to_bytes())
+            .await?;
+        self.events.add_uploaded(length);
+        Ok(())
+    }
+
+    async fn handle_extension_message(&mut self, payload: &[u8]) -> Result<(), Error> {
+        match payload.first() {
+            Some(0) => {
+                let extensions = ExtensionPayload::from_bytes(payload)?;
+                if let Some(reqq) = extensions.get_reqq() {
+                    self.pipeline.set_peer_limit(reqq);
+                }
+                self.peer_extensions = Some(extensions);
+                if self.incoming {
+                    self.update_peer_pool();
+                }
+            }
+            Some(&UT_METADATA_ID) => {
+                let (message, _) = MetadataMessage::from_bytes(&payload[1..])?;
+                if message.message_type()? != MetadataMessageType::Request {
+                    return Ok(());
+                }
+                let Some(extension_id) = self
+                    .peer_extensions
+                    .as_ref()
+                    .and_then(|extensions| extensions.get_extension("ut_metadata"))
+                else {
+                    return Ok(());
+                };
+                let reply =
+                    metadata::respond(Some(&self.metadata), message.piece, extension_id as u8);
+                self.stream
+                    .as_mut()
+                    .unwrap()
+                    .send_message(MessageId::Extension, reply)
+                    .await?;
+            }
+            Some(&UT_PEX_ID) => {
+                if self.torrent.is_private() || !self.pex.accept_message() {
+                    return Ok(());
+                }
+                if let Some(peer_pool) = &self.peer_pool {
+                    let message = PexMessage::from_bytes(&payload[1..])?;
+                    peer_pool.lock().unwrap().add_pex_message(&message);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let (Some(peer_pool), Some(pooled)) = (&self.peer_pool, self.pooled) {
+            peer_pool.lock().unwrap().mark_disconnected(pooled);
+        }
+        let outstanding = self.pipeline.clear();
+        self.release(&outstanding);
+        if let Some(picker) = &self.picker {
+            picker.lock().unwrap().remove_peer(&self.state.bitfield);
+        }
     }
 }
@@ -0,0 +1,97 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    // the tracker's answer: how many peers it gave us, or why it failed
+    Announce {
+        tracker: String,
+        result: Result<usize, String>,
+    },
+    // progress loaded from a resume record
+    Resumed {
+        completed: usize,
+        total: usize,
+    },
+    // data already in storage was hash-checked and `good` pieces of it are intact
+    Checked {
+        good: usize,
+        total: usize,
+    },
+    // a download is starting with `completed` of its `total` pieces already verified
+    Started {
+        completed: usize,
+        total: usize,
+    },
+    PeerConnected(SocketAddr),
+    // sent for every peer we tried, including ones we never got connected to, with the
+    // error that ended the connection if there was one
+    PeerDisconnected {
+        peer: SocketAddr,
+        error: Option<String>,
+    },
+    PieceCompleted {
+        piece: u32,
+        completed: usize,
+        total: usize,
+    },
+    HashFailed {
+        piece: u32,
+        peer: SocketAddr,
+    },
+    // every peer at this address is dropped for sending corrupt data
+    PeerBanned(IpAddr),
+    // payload bytes moved so far, sent about once a second while downloading
+    Transferred {
+        downloaded: u64,
+        uploaded: u64,
+    },
+}
+
+// Where the download engine reports what it's doing. Clones share the channel and the byte
+// counters; without a subscriber events are dropped, but bytes are still counted.
+#[derive(Debug, Clone, Default)]
+pub struct Events {
+    // unbounded so a slow consumer never stalls the download
+    tx: Option<UnboundedSender<Event>>,
+    downloaded: Arc<AtomicU64>,
+    uploaded: Arc<AtomicU64>,
+}
+
+impl Events {
+    // Starts sending events to a new receiver, replacing any earlier one.
+    pub fn subscribe(&mut self) -> UnboundedReceiver<Event> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.tx = Some(tx);
+        rx
+    }
+
+    pub fn send(&self, event: Event) {
+        if let Some(tx) = &self.tx {
+            // the receiver may be gone, which just means nobody is listening any more
+            let _ = tx.send(event);
+        }
+    }
+
+    pub fn add_downloaded(&self, bytes: usize) {
+        self.downloaded.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_uploaded(&self, bytes: usize) {
+        self.uploaded.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn send_transferred(&self) {
+        self.send(Event::Transferred {
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+        });
+    }
+}
@@ -1,2 +1,11 @@
+pub mod bitfield;
 pub mod client;
+pub mod events;
+pub mod peer;
+pub mod picker;
+pub mod pipeline;
+pub mod reader;
+pub mod smartban;
+pub mod swarm;
+#[allow(clippy::module_inception)]
 pub mod torrent;
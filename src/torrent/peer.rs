@@ -0,0 +1,31 @@
+use crate::torrent::bitfield::Bitfield;
+
+// Protocol state of one peer connection. Both sides start out choking and not interested.
+#[derive(Debug)]
+pub struct PeerState {
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+    pub bitfield: Bitfield,
+}
+
+impl PeerState {
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            bitfield: Bitfield::new(piece_count),
+        }
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.bitfield.has(index)
+    }
+
+    pub fn is_seed(&self) -> bool {
+        self.bitfield.is_complete()
+    }
+}
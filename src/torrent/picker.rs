@@ -0,0 +1,760 @@
+use std::{cmp::min, collections::BTreeMap};
+
+use bytes::Bytes;
+use rand::{rng, seq::IndexedRandom};
+use sha1::{Digest, Sha1};
+use tokio::sync::watch;
+
+use crate::torrent::bitfield::Bitfield;
+use crate::torrent::pipeline::{BlockRequest, RequestPipeline, BLOCK_SIZE};
+use crate::torrent::torrent::Torrent;
+
+// number of pieces picked at random before switching to rarest-first, so a new peer quickly
+// has something to trade
+const RANDOM_FIRST_PIECES: usize = 4;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum Priority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PickStrategy {
+    #[default]
+    RarestFirst,
+    RandomFirst,
+    Sequential,
+}
+
+impl PickStrategy {
+    pub fn build(&self) -> Box<dyn PieceStrategy> {
+        match self {
+            PickStrategy::RarestFirst => Box::new(RarestFirst),
+            PickStrategy::RandomFirst => Box::new(RandomFirst),
+            PickStrategy::Sequential => Box::new(Sequential),
+        }
+    }
+}
+
+// Chooses which new piece to start. `candidates` all have the same, highest priority and are
+// never empty; `availability` counts the connected peers that have each piece.
+pub trait PieceStrategy: Send {
+    fn choose(&mut self, candidates: &[u32], availability: &[u32], completed: usize) -> u32;
+}
+
+pub struct RarestFirst;
+
+impl PieceStrategy for RarestFirst {
+    fn choose(&mut self, candidates: &[u32], availability: &[u32], _completed: usize) -> u32 {
+        let rarest = candidates
+            .iter()
+            .map(|piece| availability[*piece as usize])
+            .min()
+            .unwrap();
+        let rarest: Vec<u32> = candidates
+            .iter()
+            .copied()
+            .filter(|piece| availability[*piece as usize] == rarest)
+            .collect();
+        *rarest.choose(&mut rng()).unwrap()
+    }
+}
+
+pub struct RandomFirst;
+
+impl PieceStrategy for RandomFirst {
+    fn choose(&mut self, candidates: &[u32], availability: &[u32], completed: usize) -> u32 {
+        if completed < RANDOM_FIRST_PIECES {
+            *candidates.choose(&mut rng()).unwrap()
+        } else {
+            RarestFirst.choose(candidates, availability, completed)
+        }
+    }
+}
+
+pub struct Sequential;
+
+impl PieceStrategy for Sequential {
+    fn choose(&mut self, candidates: &[u32], _availability: &[u32], _completed: usize) -> u32 {
+        *candidates.iter().min().unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BlockState {
+    Missing,
+    // number of peers the block is requested from; more than one only in endgame
+    Requested(u32),
+    Received,
+}
+
+#[derive(Debug)]
+struct ActivePiece {
+    blocks: Vec<BlockState>,
+    // hash of the piece's first `hashed` bytes, fed as blocks arrive so the piece rarely
+    // has to be read back to verify it
+    hasher: Sha1,
+    hashed: u32,
+    // blocks that arrived ahead of a gap, held (not copied) until it's filled
+    pending: BTreeMap<u32, Bytes>,
+}
+
+impl ActivePiece {
+    fn new(blocks: Vec<BlockState>) -> Self {
+        Self {
+            blocks,
+            hasher: Sha1::new(),
+            hashed: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    fn hash_block(&mut self, begin: u32, data: &Bytes) {
+        if begin != self.hashed {
+            self.pending.insert(begin, data.clone());
+            return;
+        }
+        self.hasher.update(data);
+        self.hashed += data.len() as u32;
+        while let Some(data) = self.pending.remove(&self.hashed) {
+            self.hasher.update(&data);
+            self.hashed += data.len() as u32;
+        }
+    }
+}
+
+// Decides which blocks to request from which peer across the whole swarm. Partially
+// downloaded pieces are finished before new ones are started.
+pub struct PiecePicker {
+    strategy: Box<dyn PieceStrategy>,
+    piece_lengths: Vec<u32>,
+    availability: Vec<u32>,
+    priorities: Vec<Priority>,
+    // readers currently waiting on or reading ahead into each piece, which makes it high
+    // priority without touching the priority it was given
+    boosts: Vec<u32>,
+    have: Bitfield,
+    active: BTreeMap<u32, ActivePiece>,
+    // bumped when a piece is verified or a block arrives that other peers were also asked for
+    changed: watch::Sender<u64>,
+}
+
+impl PiecePicker {
+    pub fn new(torrent: &Torrent) -> Self {
+        let piece_count = torrent.get_piece_count();
+        Self {
+            strategy: PickStrategy::default().build(),
+            piece_lengths: (0..piece_count)
+                .map(|i| torrent.get_piece_length(i))
+                .collect(),
+            availability: vec![0; piece_count],
+            priorities: vec![Priority::default(); piece_count],
+            boosts: vec![0; piece_count],
+            have: Bitfield::new(piece_count),
+            active: BTreeMap::new(),
+            changed: watch::channel(0).0,
+        }
+    }
+
+    pub fn set_strategy(&mut self, strategy: Box<dyn PieceStrategy>) {
+        self.strategy = strategy;
+    }
+
+    pub fn set_priority(&mut self, piece: u32, priority: Priority) {
+        if let Some(p) = self.priorities.get_mut(piece as usize) {
+            *p = priority;
+        }
+    }
+
+    pub fn priority(&self, piece: u32) -> Priority {
+        self.priorities[piece as usize]
+    }
+
+    pub fn boost(&mut self, piece: u32) {
+        if let Some(count) = self.boosts.get_mut(piece as usize) {
+            *count += 1;
+        }
+    }
+
+    pub fn unboost(&mut self, piece: u32) {
+        if let Some(count) = self.boosts.get_mut(piece as usize) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    // The priority a piece is picked with: high while boosted, unless it's skipped.
+    fn pick_priority(&self, piece: u32) -> Priority {
+        match self.priorities[piece as usize] {
+            Priority::Skip => Priority::Skip,
+            _ if self.boosts[piece as usize] > 0 => Priority::High,
+            priority => priority,
+        }
+    }
+
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn add_peer(&mut self, bitfield: &Bitfield) {
+        for piece in bitfield.iter() {
+            self.availability[piece] += 1;
+        }
+    }
+
+    pub fn remove_peer(&mut self, bitfield: &Bitfield) {
+        for piece in bitfield.iter() {
+            self.availability[piece] = self.availability[piece].saturating_sub(1);
+        }
+    }
+
+    pub fn peer_has(&mut self, piece: u32) {
+        if let Some(count) = self.availability.get_mut(piece as usize) {
+            *count += 1;
+        }
+    }
+
+    fn is_wanted(&self, piece: usize) -> bool {
+        self.priorities[piece] != Priority::Skip && !self.have.has(piece)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        (0..self.piece_lengths.len()).all(|piece| !self.is_wanted(piece))
+    }
+
+    // Whether the peer has any piece we still want.
+    pub fn is_interesting(&self, peer: &Bitfield) -> bool {
+        peer.iter().any(|piece| self.is_wanted(piece))
+    }
+
+    // `outstanding` is what's already requested from this peer, so endgame doesn't ask it for
+    // the same block twice.
+    pub fn next_request(
+        &mut self,
+        peer: &Bitfield,
+        outstanding: &RequestPipeline,
+    ) -> Option<BlockRequest> {
+        // finish what's started first, highest priority first
+        let partial = self
+            .active
+            .iter()
+            .filter(|(piece, active)| {
+                peer.has(**piece as usize) && active.blocks.contains(&BlockState::Missing)
+            })
+            .max_by_key(|(piece, _)| self.pick_priority(**piece))
+            .map(|(piece, _)| *piece);
+        if let Some(piece) = partial {
+            return self.request_block(piece);
+        }
+
+        let candidates: Vec<u32> = peer
+            .iter()
+            .filter(|piece| self.is_wanted(*piece) && !self.active.contains_key(&(*piece as u32)))
+            .map(|piece| piece as u32)
+            .collect();
+        let Some(priority) = candidates
+            .iter()
+            .map(|piece| self.pick_priority(*piece))
+            .max()
+        else {
+            return self.endgame_request(peer, outstanding);
+        };
+        let candidates: Vec<u32> = candidates
+            .into_iter()
+            .filter(|piece| self.pick_priority(*piece) == priority)
+            .collect();
+
+        let piece = self
+            .strategy
+            .choose(&candidates, &self.availability, self.have.count());
+        let length = self.piece_lengths[piece as usize];
+        self.active.insert(
+            piece,
+            ActivePiece::new(vec![
+                BlockState::Missing;
+                length.div_ceil(BLOCK_SIZE) as usize
+            ]),
+        );
+        self.request_block(piece)
+    }
+
+    // Endgame: every remaining block is already requested, so ask this peer too for the
+    // one with the fewest requests. Whichever copy arrives first wins, the rest are cancelled.
+    fn endgame_request(
+        &mut self,
+        peer: &Bitfield,
+        outstanding: &RequestPipeline,
+    ) -> Option<BlockRequest> {
+        if !self.in_endgame() {
+            return None;
+        }
+        let (request, _) = self
+            .active
+            .iter()
+            .filter(|(piece, _)| peer.has(**piece as usize))
+            .flat_map(|(piece, active)| {
+                active
+                    .blocks
+                    .iter()
+                    .enumerate()
+                    .filter_map(move |(block, state)| match state {
+                        BlockState::Requested(count) => Some((*piece, block, *count)),
+                        _ => None,
+                    })
+            })
+            .map(|(piece, block, count)| (self.block_request(piece, block), count))
+            .filter(|(request, _)| !outstanding.contains(request))
+            .min_by_key(|(_, count)| *count)?;
+
+        let active = self.active.get_mut(&request.index)?;
+        if let BlockState::Requested(count) =
+            &mut active.blocks[(request.begin / BLOCK_SIZE) as usize]
+        {
+            *count += 1;
+        }
+        Some(request)
+    }
+
+    // True once no wanted block is left that nobody has been asked for.
+    pub fn in_endgame(&self) -> bool {
+        (0..self.piece_lengths.len()).all(|piece| {
+            !self.is_wanted(piece)
+                || self
+                    .active
+                    .get(&(piece as u32))
+                    .is_some_and(|active| !active.blocks.contains(&BlockState::Missing))
+        })
+    }
+
+    fn block_request(&self, piece: u32, block: usize) -> BlockRequest {
+        let length = self.piece_lengths[piece as usize];
+        let begin = block as u32 * BLOCK_SIZE;
+        BlockRequest {
+            index: piece,
+            begin,
+            length: min(BLOCK_SIZE, length - begin),
+        }
+    }
+
+    fn request_block(&mut self, piece: u32) -> Option<BlockRequest> {
+        let active = self.active.get_mut(&piece)?;
+        let block = active
+            .blocks
+            .iter()
+            .position(|state| *state == BlockState::Missing)?;
+        active.blocks[block] = BlockState::Requested(1);
+        Some(self.block_request(piece, block))
+    }
+
+    // Whether a block no longer needs to be downloaded, i.e. its request can be cancelled.
+    pub fn is_received(&self, request: &BlockRequest) -> bool {
+        if self.have.has(request.index as usize) {
+            return true;
+        }
+        self.active.get(&request.index).is_some_and(|active| {
+            active.blocks.get((request.begin / BLOCK_SIZE) as usize) == Some(&BlockState::Received)
+        })
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.changed.subscribe()
+    }
+
+    // Gives a block back after its request was dropped (choke, timeout, disconnect).
+    pub fn release(&mut self, request: &BlockRequest) {
+        let Some(active) = self.active.get_mut(&request.index) else {
+            return;
+        };
+        let block = (request.begin / BLOCK_SIZE) as usize;
+        match active.blocks.get(block) {
+            Some(BlockState::Requested(1)) => active.blocks[block] = BlockState::Missing,
+            Some(BlockState::Requested(count)) => {
+                active.blocks[block] = BlockState::Requested(count - 1)
+            }
+            _ => {}
+        }
+    }
+
+    // Accepts a block from any peer. Returns None if we don't need it, otherwise whether it
+    // completed its piece; the caller then reports the hash check with `piece_verified` or
+    // `piece_failed`.
+    pub fn on_block(&mut self, piece: u32, begin: u32, data: &Bytes) -> Option<bool> {
+        let length = data.len();
+        let piece_length = *self.piece_lengths.get(piece as usize)?;
+        let active = self.active.get_mut(&piece)?;
+        if begin >= piece_length
+            || !begin.is_multiple_of(BLOCK_SIZE)
+            || length as u32 != min(BLOCK_SIZE, piece_length - begin)
+        {
+            return None;
+        }
+        let block = (begin / BLOCK_SIZE) as usize;
+        let previous = *active.blocks.get(block)?;
+        if previous == BlockState::Received {
+            return None;
+        }
+        active.blocks[block] = BlockState::Received;
+        active.hash_block(begin, data);
+        if matches!(previous, BlockState::Requested(count) if count > 1) {
+            self.changed.send_modify(|count| *count += 1);
+        }
+
+        Some(
+            active
+                .blocks
+                .iter()
+                .all(|state| *state == BlockState::Received),
+        )
+    }
+
+    // The hash of a completed piece and how many bytes it covers. It stops short when some
+    // blocks never came through `on_block` (restored from a resume record); the caller
+    // hashes the rest from storage.
+    pub fn take_hash(&mut self, piece: u32) -> (Sha1, u32) {
+        match self.active.get_mut(&piece) {
+            Some(active) => (
+                std::mem::take(&mut active.hasher),
+                std::mem::take(&mut active.hashed),
+            ),
+            None => (Sha1::new(), 0),
+        }
+    }
+
+    pub fn piece_verified(&mut self, piece: u32) {
+        self.active.remove(&piece);
+        self.have.set(piece as usize);
+        self.changed.send_modify(|count| *count += 1);
+    }
+
+    // Blocks received so far for each piece that's still in progress, by offset.
+    pub fn partial_blocks(&self) -> BTreeMap<u32, Vec<u32>> {
+        self.active
+            .iter()
+            .map(|(piece, active)| {
+                let received = active
+                    .blocks
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, state)| **state == BlockState::Received)
+                    .map(|(block, _)| block as u32 * BLOCK_SIZE)
+                    .collect::<Vec<_>>();
+                (*piece, received)
+            })
+            .filter(|(_, received)| !received.is_empty())
+            .collect()
+    }
+
+    // Picks a piece back up from where an earlier run left it. A piece whose blocks were all
+    // received but never verified is simply started over.
+    pub fn restore_partial(&mut self, piece: u32, received: &[u32]) {
+        let Some(length) = self.piece_lengths.get(piece as usize) else {
+            return;
+        };
+        if self.have.has(piece as usize) {
+            return;
+        }
+        let mut blocks = vec![BlockState::Missing; length.div_ceil(BLOCK_SIZE) as usize];
+        for begin in received {
+            if let Some(state) = blocks.get_mut((begin / BLOCK_SIZE) as usize) {
+                *state = BlockState::Received;
+            }
+        }
+        if blocks.contains(&BlockState::Missing) {
+            self.active.insert(piece, ActivePiece::new(blocks));
+        }
+    }
+
+    // Starts the piece over after a failed hash check.
+    pub fn piece_failed(&mut self, piece: u32) {
+        self.active.remove(&piece);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::torrent::Info;
+
+    // eight pieces of two blocks, the last one a block and a bit
+    const PIECE_LENGTH: u32 = 2 * BLOCK_SIZE;
+    const LAST_PIECE_LENGTH: u32 = BLOCK_SIZE + 3616;
+
+    fn picker(strategy: PickStrategy) -> PiecePicker {
+        let length = 7 * PIECE_LENGTH + LAST_PIECE_LENGTH;
+        let info = [
+            format!("d6:lengthi{length}e4:name5:x.bin12:piece lengthi{PIECE_LENGTH}e6:pieces160:")
+                .as_bytes(),
+            &[0; 160],
+            b"e",
+        ]
+        .concat();
+        let mut picker =
+            PiecePicker::new(&Torrent::new("url".to_string(), Info::from_bytes(&info)));
+        picker.set_strategy(strategy.build());
+        picker
+    }
+
+    fn bitfield(pieces: impl IntoIterator<Item = usize>) -> Bitfield {
+        let mut bitfield = Bitfield::new(8);
+        for piece in pieces {
+            bitfield.set(piece);
+        }
+        bitfield
+    }
+
+    // Requests everything the peer can give us, in order, tracking it like a connection does.
+    fn request_all(
+        picker: &mut PiecePicker,
+        peer: &Bitfield,
+        outstanding: &mut RequestPipeline,
+    ) -> Vec<BlockRequest> {
+        let mut requests = vec![];
+        while let Some(request) = picker.next_request(peer, outstanding) {
+            outstanding.on_request(request);
+            requests.push(request);
+        }
+        requests
+    }
+
+    fn pieces(requests: &[BlockRequest]) -> Vec<u32> {
+        let mut pieces: Vec<u32> = requests.iter().map(|request| request.index).collect();
+        pieces.dedup();
+        pieces
+    }
+
+    #[test]
+    fn sequential_requests_pieces_in_order() {
+        let mut picker = picker(PickStrategy::Sequential);
+        let peer = Bitfield::full(8);
+        let requests = request_all(&mut picker, &peer, &mut RequestPipeline::new(64));
+
+        assert_eq!(pieces(&requests), (0..8).collect::<Vec<_>>());
+        assert_eq!(
+            requests[..2],
+            [
+                BlockRequest {
+                    index: 0,
+                    begin: 0,
+                    length: BLOCK_SIZE
+                },
+                BlockRequest {
+                    index: 0,
+                    begin: BLOCK_SIZE,
+                    length: BLOCK_SIZE
+                },
+            ]
+        );
+        assert_eq!(
+            requests.last(),
+            Some(&BlockRequest {
+                index: 7,
+                begin: BLOCK_SIZE,
+                length: LAST_PIECE_LENGTH - BLOCK_SIZE
+            })
+        );
+    }
+
+    #[test]
+    fn rarest_first_starts_the_least_available_piece() {
+        let mut picker = picker(PickStrategy::RarestFirst);
+        picker.add_peer(&Bitfield::full(8));
+        picker.add_peer(&bitfield([0, 1, 2, 3, 4, 6, 7]));
+        picker.add_peer(&bitfield([0, 1, 2, 4, 6, 7]));
+        picker.peer_has(3);
+        picker.peer_has(6);
+
+        // piece 5 is on one peer, 3 and 6 on three, the rest on two
+        let peer = Bitfield::full(8);
+        let mut outstanding = RequestPipeline::new(64);
+        assert_eq!(picker.next_request(&peer, &outstanding).unwrap().index, 5);
+        outstanding.on_request(picker.next_request(&peer, &outstanding).unwrap());
+        let next = picker.next_request(&peer, &outstanding).unwrap().index;
+        assert!([0, 1, 2, 4, 7].contains(&next));
+    }
+
+    #[test]
+    fn random_first_turns_rarest_first_after_a_few_pieces() {
+        let candidates: Vec<u32> = (0..8).collect();
+        let availability = [2, 2, 2, 2, 2, 2, 1, 2];
+
+        let chosen: std::collections::HashSet<u32> = (0..100)
+            .map(|_| RandomFirst.choose(&candidates, &availability, RANDOM_FIRST_PIECES - 1))
+            .collect();
+        assert!(chosen.len() > 1);
+        for _ in 0..100 {
+            assert_eq!(
+                RandomFirst.choose(&candidates, &availability, RANDOM_FIRST_PIECES),
+                6
+            );
+        }
+
+        // the picker counts the pieces it has verified
+        let mut picker = picker(PickStrategy::RandomFirst);
+        picker.add_peer(&Bitfield::full(8));
+        picker.add_peer(&bitfield([0, 1, 2, 3, 4, 5, 7]));
+        for piece in 0..RANDOM_FIRST_PIECES as u32 {
+            picker.piece_verified(piece);
+        }
+        let peer = Bitfield::full(8);
+        let request = picker.next_request(&peer, &RequestPipeline::new(64));
+        assert_eq!(request.unwrap().index, 6);
+    }
+
+    #[test]
+    fn higher_priorities_come_first_and_skipped_pieces_never() {
+        let mut picker = picker(PickStrategy::Sequential);
+        picker.set_priority(0, Priority::Skip);
+        picker.set_priority(1, Priority::Low);
+        picker.set_priority(3, Priority::High);
+        picker.set_priority(6, Priority::Skip);
+        picker.boost(5);
+        picker.boost(6);
+
+        let peer = Bitfield::full(8);
+        let requests = request_all(&mut picker, &peer, &mut RequestPipeline::new(64));
+        // a boost counts as high, but doesn't bring back a skipped piece
+        assert_eq!(pieces(&requests), vec![3, 5, 2, 4, 7, 1]);
+        assert_eq!(picker.priority(5), Priority::Normal);
+    }
+
+    #[test]
+    fn partial_pieces_are_finished_before_new_ones_are_started() {
+        let mut picker = picker(PickStrategy::Sequential);
+        picker.restore_partial(2, &[0]);
+        picker.restore_partial(6, &[BLOCK_SIZE]);
+        picker.set_priority(2, Priority::Low);
+        picker.set_priority(6, Priority::High);
+        picker.set_priority(7, Priority::High);
+        let outstanding = RequestPipeline::new(64);
+
+        // only the peer's own pieces count, and the higher priority partial piece goes first
+        let request = picker
+            .next_request(&bitfield([0, 1]), &outstanding)
+            .unwrap();
+        assert_eq!((request.index, request.begin), (0, 0));
+        let peer = Bitfield::full(8);
+        let request = picker.next_request(&peer, &outstanding).unwrap();
+        assert_eq!((request.index, request.begin), (6, 0));
+        let request = picker.next_request(&peer, &outstanding).unwrap();
+        assert_eq!((request.index, request.begin), (0, BLOCK_SIZE));
+        let request = picker.next_request(&peer, &outstanding).unwrap();
+        // even a low priority partial piece goes before a new high priority one
+        assert_eq!((request.index, request.begin), (2, BLOCK_SIZE));
+        let request = picker.next_request(&peer, &outstanding).unwrap();
+        assert_eq!((request.index, request.begin), (7, 0));
+    }
+
+    #[test]
+    fn endgame_starts_once_every_wanted_block_is_requested() {
+        let mut picker = picker(PickStrategy::Sequential);
+        picker.set_priority(7, Priority::Skip);
+        let mut outstanding = RequestPipeline::new(64);
+        assert!(!picker.in_endgame());
+
+        let requests = request_all(&mut picker, &bitfield(0..6), &mut outstanding);
+        assert_eq!(requests.len(), 12);
+        assert!(!picker.in_endgame());
+        picker.piece_verified(6);
+        assert!(picker.in_endgame());
+
+        // the peer isn't asked twice for the same block
+        assert_eq!(picker.next_request(&Bitfield::full(8), &outstanding), None);
+        picker.release(&requests[3]);
+        assert!(!picker.in_endgame());
+        assert_eq!(
+            picker.next_request(&Bitfield::full(8), &outstanding),
+            Some(requests[3])
+        );
+    }
+
+    #[test]
+    fn endgame_requests_each_block_from_more_peers_in_turn() {
+        let mut picker = picker(PickStrategy::Sequential);
+        let peer = bitfield([0, 1]);
+        let first = request_all(&mut picker, &peer, &mut RequestPipeline::new(64));
+        assert_eq!(first.len(), 4);
+        for piece in 1..8 {
+            picker.piece_verified(piece);
+        }
+
+        // a second peer gets every block that has only been asked for once
+        let mut outstanding = RequestPipeline::new(64);
+        let second = picker.next_request(&peer, &outstanding).unwrap();
+        outstanding.on_request(second);
+        assert_eq!(
+            picker.active[&0].blocks,
+            [BlockState::Requested(2), BlockState::Requested(1)]
+        );
+        let third = picker.next_request(&peer, &outstanding).unwrap();
+        outstanding.on_request(third);
+        assert_ne!(second, third);
+        assert_eq!(picker.active[&0].blocks, [BlockState::Requested(2); 2]);
+        assert_eq!(picker.next_request(&peer, &outstanding), None);
+
+        // a third peer starts over with the least requested block
+        picker.release(&second);
+        let mut outstanding = RequestPipeline::new(64);
+        assert_eq!(picker.next_request(&peer, &outstanding), Some(second));
+        outstanding.on_request(second);
+        assert_eq!(picker.next_request(&peer, &outstanding), Some(third));
+        assert_eq!(
+            picker.active[&0].blocks,
+            [BlockState::Requested(2), BlockState::Requested(3)]
+        );
+
+        // dropping every request makes the block missing again
+        picker.release(&second);
+        picker.release(&second);
+        assert_eq!(picker.active[&0].blocks[0], BlockState::Missing);
+        picker.release(&second);
+        assert_eq!(picker.active[&0].blocks[0], BlockState::Missing);
+    }
+
+    #[test]
+    fn duplicate_endgame_blocks_are_reported_once() {
+        let mut picker = picker(PickStrategy::Sequential);
+        let peer = bitfield([0]);
+        let first = request_all(&mut picker, &peer, &mut RequestPipeline::new(64));
+        for piece in 1..8 {
+            picker.piece_verified(piece);
+        }
+        let mut changed = picker.subscribe();
+        changed.mark_unchanged();
+        let duplicate = picker
+            .next_request(&peer, &RequestPipeline::new(64))
+            .unwrap();
+        assert_eq!(duplicate, first[0]);
+
+        let data = Bytes::from(vec![0; BLOCK_SIZE as usize]);
+        assert_eq!(picker.on_block(0, 0, &data), Some(false));
+        // the other peer's copy can now be cancelled
+        assert!(changed.has_changed().unwrap());
+        assert!(picker.is_received(&duplicate));
+        assert_eq!(picker.on_block(0, 0, &data), None);
+        assert!(!picker.is_received(&first[1]));
+    }
+
+    #[test]
+    fn blocks_must_match_a_block_of_an_active_piece() {
+        let mut picker = picker(PickStrategy::Sequential);
+        let block = Bytes::from(vec![0; BLOCK_SIZE as usize]);
+        let tail = Bytes::from(vec![0; (LAST_PIECE_LENGTH - BLOCK_SIZE) as usize]);
+        assert_eq!(picker.on_block(7, 0, &block), None);
+        request_all(&mut picker, &bitfield([7]), &mut RequestPipeline::new(64));
+
+        assert_eq!(picker.on_block(8, 0, &block), None);
+        assert_eq!(picker.on_block(7, 1, &block), None);
+        assert_eq!(picker.on_block(7, 0, &block.slice(1..)), None);
+        assert_eq!(picker.on_block(7, BLOCK_SIZE, &block), None);
+        assert_eq!(picker.on_block(7, 2 * BLOCK_SIZE, &tail), None);
+        assert!(picker.partial_blocks().is_empty());
+
+        assert_eq!(picker.on_block(7, BLOCK_SIZE, &tail), Some(false));
+        assert_eq!(picker.on_block(7, 0, &block), Some(true));
+        // hashed in order even though the blocks weren't
+        assert_eq!(picker.take_hash(7).1, LAST_PIECE_LENGTH);
+    }
+}
@@ -0,0 +1,171 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+pub const BLOCK_SIZE: u32 = 1 << 14;
+pub const DEFAULT_MAX_REQUESTS: usize = 64;
+
+const MIN_DEPTH: usize = 4;
+const INITIAL_DEPTH: usize = 8;
+// enough requests to cover this much time at the measured download rate
+const QUEUE_TIME: f64 = 3.0;
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+// Tracks the requests outstanding to one peer and how many we should keep in flight.
+#[derive(Debug)]
+pub struct RequestPipeline {
+    max_depth: usize,
+    peer_limit: Option<usize>,
+    depth: usize,
+    outstanding: HashMap<(u32, u32), (BlockRequest, Instant)>,
+    rate: f64,
+    window_bytes: usize,
+    window_start: Instant,
+}
+
+impl RequestPipeline {
+    pub fn new(max_depth: usize) -> Self {
+        let max_depth = max_depth.max(1);
+        Self {
+            max_depth,
+            peer_limit: None,
+            depth: INITIAL_DEPTH.min(max_depth),
+            outstanding: HashMap::new(),
+            rate: 0.0,
+            window_bytes: 0,
+            window_start: Instant::now(),
+        }
+    }
+
+    // Caps the depth at the `reqq` the peer advertised in its extension handshake.
+    pub fn set_peer_limit(&mut self, reqq: usize) {
+        self.peer_limit = Some(reqq.max(1));
+        self.depth = self.depth.min(self.limit());
+    }
+
+    fn limit(&self) -> usize {
+        self.peer_limit
+            .map_or(self.max_depth, |reqq| reqq.min(self.max_depth))
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn can_request(&self) -> bool {
+        self.outstanding.len() < self.depth
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outstanding.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    pub fn contains(&self, request: &BlockRequest) -> bool {
+        self.outstanding
+            .contains_key(&(request.index, request.begin))
+    }
+
+    pub fn requests(&self) -> Vec<BlockRequest> {
+        self.outstanding
+            .values()
+            .map(|(request, _)| *request)
+            .collect()
+    }
+
+    pub fn on_request(&mut self, request: BlockRequest) {
+        self.outstanding
+            .insert((request.index, request.begin), (request, Instant::now()));
+    }
+
+    // Matches an incoming block to its request, in any order. Returns None for blocks we
+    // never asked for (or already gave up on). A block of the wrong length leaves its request
+    // outstanding, so it's released to the picker when it expires.
+    pub fn on_block(&mut self, index: u32, begin: u32, length: usize) -> Option<BlockRequest> {
+        let (request, _) = self.outstanding.get(&(index, begin))?;
+        if request.length as usize != length {
+            return None;
+        }
+        let (request, _) = self.outstanding.remove(&(index, begin))?;
+        self.record_bytes(length);
+        Some(request)
+    }
+
+    pub fn remove(&mut self, index: u32, begin: u32) -> Option<BlockRequest> {
+        self.outstanding
+            .remove(&(index, begin))
+            .map(|(request, _)| request)
+    }
+
+    // Drops every outstanding request, e.g. when the peer chokes us.
+    pub fn clear(&mut self) -> Vec<BlockRequest> {
+        self.outstanding
+            .drain()
+            .map(|(_, (request, _))| request)
+            .collect()
+    }
+
+    // Removes requests the peer has sat on for too long so they can be sent again.
+    pub fn expire(&mut self) -> Vec<BlockRequest> {
+        let now = Instant::now();
+        let expired: Vec<BlockRequest> = self
+            .outstanding
+            .values()
+            .filter(|(_, sent_at)| now.duration_since(*sent_at) >= REQUEST_TIMEOUT)
+            .map(|(request, _)| *request)
+            .collect();
+        if !expired.is_empty() {
+            // a peer that stalls doesn't deserve a deep queue
+            self.depth = MIN_DEPTH.min(self.limit());
+        }
+        for request in &expired {
+            self.outstanding.remove(&(request.index, request.begin));
+        }
+        expired
+    }
+
+    // Time until the oldest outstanding request expires.
+    pub fn next_timeout(&self) -> Duration {
+        self.outstanding
+            .values()
+            .map(|(_, sent_at)| REQUEST_TIMEOUT.saturating_sub(sent_at.elapsed()))
+            .min()
+            .unwrap_or(REQUEST_TIMEOUT)
+    }
+
+    fn record_bytes(&mut self, length: usize) {
+        self.window_bytes += length;
+        let elapsed = self.window_start.elapsed();
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+
+        let sample = self.window_bytes as f64 / elapsed.as_secs_f64();
+        self.rate = if self.rate == 0.0 {
+            sample
+        } else {
+            0.5 * self.rate + 0.5 * sample
+        };
+        self.window_bytes = 0;
+        self.window_start = Instant::now();
+
+        let wanted = (self.rate * QUEUE_TIME / BLOCK_SIZE as f64).ceil() as usize;
+        self.depth = wanted.clamp(MIN_DEPTH.min(self.limit()), self.limit());
+    }
+
+    pub fn download_rate(&self) -> f64 {
+        self.rate
+    }
+}
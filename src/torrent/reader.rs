@@ -0,0 +1,244 @@
+use std::{
+    future::Future,
+    io::{self, SeekFrom},
+    ops::Range,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use anyhow::Error;
+use tokio::{
+    io::{AsyncRead, AsyncSeek, ReadBuf},
+    sync::watch,
+    task::JoinHandle,
+};
+
+use crate::storage::Storage;
+use crate::torrent::{picker::PiecePicker, swarm::Swarm};
+
+pub const DEFAULT_READ_AHEAD: u64 = 4 << 20;
+
+type Wait = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// Downloads a set of pieces in the background for any number of readers. Stops when dropped.
+pub struct BackgroundDownload {
+    // set once the download stops, with its error if it failed
+    done: watch::Receiver<Option<Result<(), String>>>,
+    task: JoinHandle<()>,
+}
+
+impl BackgroundDownload {
+    pub fn start(swarm: Arc<Swarm>, pieces: Vec<u32>) -> Arc<Self> {
+        let (done_tx, done) = watch::channel(None);
+        let task = tokio::spawn(async move {
+            let result = swarm.download_pieces(pieces).await;
+            let _ = done_tx.send(Some(result.map_err(|e| e.to_string())));
+        });
+        Arc::new(Self { done, task })
+    }
+
+    pub fn failed(&self) -> bool {
+        matches!(*self.done.borrow(), Some(Err(_)))
+    }
+}
+
+impl Drop for BackgroundDownload {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+// Reads torrent content like a file while it downloads. The swarm fetches the whole torrent
+// (or one file of it) in the background; pieces under and just ahead of the read cursor are
+// bumped to high priority, and reads wait until the piece they need is verified.
+pub struct TorrentReader {
+    picker: Arc<Mutex<PiecePicker>>,
+    storage: Arc<Mutex<dyn Storage>>,
+    // the part of the torrent's content being read
+    range: Range<u64>,
+    piece_length: u64,
+    position: u64,
+    read_ahead: u64,
+    boosted: Range<u32>,
+    verified: watch::Receiver<u64>,
+    done: watch::Receiver<Option<Result<(), String>>>,
+    _download: Arc<BackgroundDownload>,
+    wait: Option<Wait>,
+}
+
+impl TorrentReader {
+    // Reads the whole torrent, or just file `file` of it, downloading only what it covers.
+    pub fn new(swarm: Arc<Swarm>, file: Option<usize>) -> Result<Self, Error> {
+        let range = Self::content_range(&swarm, file)?;
+        let pieces = swarm.storage().lock().unwrap().layout().pieces_in(&range)?;
+        let download = BackgroundDownload::start(swarm.clone(), pieces);
+        Self::with_download(swarm, file, download)
+    }
+
+    // Like `new`, but reads from a download that's already running, so several readers can
+    // share one swarm.
+    pub fn with_download(
+        swarm: Arc<Swarm>,
+        file: Option<usize>,
+        download: Arc<BackgroundDownload>,
+    ) -> Result<Self, Error> {
+        let range = Self::content_range(&swarm, file)?;
+        let picker = swarm.picker();
+        let verified = picker.lock().unwrap().subscribe();
+        let storage = swarm.storage();
+        let piece_length = storage.lock().unwrap().layout().piece_length;
+        Ok(Self {
+            picker,
+            storage,
+            range,
+            piece_length,
+            position: 0,
+            read_ahead: DEFAULT_READ_AHEAD,
+            boosted: 0..0,
+            verified,
+            done: download.done.clone(),
+            _download: download,
+            wait: None,
+        })
+    }
+
+    fn content_range(swarm: &Swarm, file: Option<usize>) -> Result<Range<u64>, Error> {
+        let storage = swarm.storage();
+        let storage = storage.lock().unwrap();
+        let layout = storage.layout();
+        match file {
+            Some(file) => {
+                let length = layout
+                    .files
+                    .get(file)
+                    .ok_or_else(|| anyhow::anyhow!("No file {} in torrent", file))?
+                    .length;
+                layout.file_range(file, 0..length)
+            }
+            None => Ok(0..layout.total_length),
+        }
+    }
+
+    pub fn set_read_ahead(&mut self, bytes: u64) {
+        self.read_ahead = bytes;
+    }
+
+    pub fn len(&self) -> u64 {
+        self.range.end - self.range.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.range.is_empty()
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    // Boosts the pieces from the cursor to the end of the read-ahead window to high priority,
+    // and releases the ones left behind.
+    fn boost(&mut self, start: u64) {
+        let end = (start + self.read_ahead.max(1)).min(self.range.end);
+        let wanted = (start / self.piece_length) as u32..end.div_ceil(self.piece_length) as u32;
+        if wanted == self.boosted {
+            return;
+        }
+        let mut picker = self.picker.lock().unwrap();
+        for piece in self.boosted.clone().filter(|piece| !wanted.contains(piece)) {
+            picker.unboost(piece);
+        }
+        for piece in wanted.clone().filter(|piece| !self.boosted.contains(piece)) {
+            picker.boost(piece);
+        }
+        self.boosted = wanted;
+    }
+}
+
+impl AsyncRead for TorrentReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let start = self.range.start + self.position;
+            if start >= self.range.end || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(wait) = self.wait.as_mut() {
+                if wait.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                self.wait = None;
+            }
+
+            self.boost(start);
+            let piece = (start / self.piece_length) as u32;
+            // marked seen before checking, so a piece verified in between still wakes us
+            self.verified.borrow_and_update();
+            if !self.picker.lock().unwrap().have().has(piece as usize) {
+                if let Some(result) = self.done.borrow().clone() {
+                    let reason = result
+                        .err()
+                        .unwrap_or_else(|| "download stopped".to_string());
+                    return Poll::Ready(Err(io::Error::other(reason)));
+                }
+                let mut verified = self.verified.clone();
+                let mut done = self.done.clone();
+                self.wait = Some(Box::pin(async move {
+                    tokio::select! {
+                        _ = verified.changed() => {}
+                        _ = done.changed() => {}
+                    }
+                }));
+                continue;
+            }
+
+            let piece_end = (piece as u64 + 1) * self.piece_length;
+            let end = piece_end
+                .min(self.range.end)
+                .min(start + buf.remaining() as u64);
+            let data = self
+                .storage
+                .lock()
+                .unwrap()
+                .read_range(start..end)
+                .map_err(io::Error::other)?;
+            buf.put_slice(&data);
+            self.position += data.len() as u64;
+            return Poll::Ready(Ok(()));
+        }
+    }
+}
+
+impl AsyncSeek for TorrentReader {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        let Some(position) = position else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            ));
+        };
+        self.position = position;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+impl Drop for TorrentReader {
+    fn drop(&mut self) {
+        let mut picker = self.picker.lock().unwrap();
+        for piece in self.boosted.clone() {
+            picker.unboost(piece);
+        }
+    }
+}
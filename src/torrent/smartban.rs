@@ -0,0 +1,253 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::IpAddr,
+};
+
+use anyhow::Error;
+use sha1::{Digest, Sha1};
+
+// failed pieces a peer can send all by itself before it's banned, unless it's cleared by the
+// piece passing later with its blocks intact
+pub const MAX_STRIKES: u32 = 3;
+
+// Works out which peers send corrupt data. Every block is attributed to the peer that sent
+// it. When a piece fails its hash check the hash of every block is kept; once the piece
+// passes, any peer whose earlier block differs from the good data is banned outright. A piece
+// that one peer sent entirely is its fault whether or not it ever passes, so that peer also
+// gets a strike; peers that only shared a failed piece are never blamed before it passes.
+#[derive(Debug, Default)]
+pub struct SmartBan {
+    // who sent each block (by offset) of the pieces being downloaded, and how long it was
+    blocks: HashMap<u32, BTreeMap<u32, (IpAddr, usize)>>,
+    failed: HashMap<u32, Vec<FailedAttempt>>,
+    strikes: HashMap<IpAddr, u32>,
+    banned: HashSet<IpAddr>,
+}
+
+#[derive(Debug)]
+struct FailedAttempt {
+    // who sent each block and the hash of what they sent
+    blocks: Vec<FailedBlock>,
+    // whether the contributors got strikes for it
+    struck: bool,
+}
+
+#[derive(Debug)]
+struct FailedBlock {
+    begin: u32,
+    length: usize,
+    peer: IpAddr,
+    hash: [u8; 20],
+}
+
+impl SmartBan {
+    pub fn on_block(&mut self, piece: u32, begin: u32, length: usize, peer: IpAddr) {
+        self.blocks
+            .entry(piece)
+            .or_default()
+            .insert(begin, (peer, length));
+    }
+
+    pub fn strikes(&self, peer: IpAddr) -> u32 {
+        self.strikes.get(&peer).copied().unwrap_or(0)
+    }
+
+    // Records a piece that failed its hash check, given the data that failed. Returns the
+    // peer that sent it, if that was just one peer and it now has too many strikes.
+    pub fn piece_failed(&mut self, piece: u32, data: &[u8]) -> Vec<IpAddr> {
+        let blocks = self.blocks.remove(&piece).unwrap_or_default();
+        let mut contributors = blocks.values().map(|(peer, _)| *peer);
+        // a peer that's already banned needs no more strikes
+        let sole = contributors.next().filter(|first| {
+            contributors.all(|peer| peer == *first) && !self.banned.contains(first)
+        });
+        let mut attempt = FailedAttempt {
+            blocks: Vec::new(),
+            struck: sole.is_some(),
+        };
+        for (begin, (peer, length)) in blocks {
+            let Some(block) = data.get(begin as usize..begin as usize + length) else {
+                continue;
+            };
+            attempt.blocks.push(FailedBlock {
+                begin,
+                length,
+                peer,
+                hash: Sha1::digest(block).into(),
+            });
+        }
+        self.failed.entry(piece).or_default().push(attempt);
+        let Some(peer) = sole else {
+            return Vec::new();
+        };
+
+        let strikes = self.strikes.entry(peer).or_default();
+        *strikes += 1;
+        if *strikes < MAX_STRIKES {
+            return Vec::new();
+        }
+        self.banned.insert(peer);
+        vec![peer]
+    }
+
+    // Records a piece that passed. If it failed before, `read` fetches the good data and the
+    // peers whose earlier blocks don't match it are returned for banning.
+    pub fn piece_verified(
+        &mut self,
+        piece: u32,
+        read: impl FnOnce() -> Result<Vec<u8>, Error>,
+    ) -> Result<Vec<IpAddr>, Error> {
+        self.blocks.remove(&piece);
+        let Some(attempts) = self.failed.remove(&piece) else {
+            return Ok(Vec::new());
+        };
+        let data = read()?;
+        let mut culprits = HashSet::new();
+        // each attempt an innocent peer sent alone cost it a strike
+        let mut cleared = Vec::new();
+        for attempt in attempts {
+            let mut contributors = HashSet::new();
+            for block in attempt.blocks {
+                let start = block.begin as usize;
+                let Some(good) = data.get(start..start + block.length) else {
+                    continue;
+                };
+                if Sha1::digest(good).as_slice() == block.hash {
+                    contributors.insert(block.peer);
+                } else {
+                    culprits.insert(block.peer);
+                }
+            }
+            if attempt.struck {
+                cleared.extend(contributors);
+            }
+        }
+        // a peer that sent one bad block is guilty however many good ones it sent
+        for peer in cleared.iter().filter(|peer| !culprits.contains(*peer)) {
+            if let Some(strikes) = self.strikes.get_mut(peer) {
+                *strikes = strikes.saturating_sub(1);
+            }
+        }
+        self.banned.extend(&culprits);
+        Ok(culprits.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HONEST: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+    const LIAR: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2));
+    const GOOD: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+    // the second block, from the liar, is corrupt
+    const BAD: [u8; 8] = [1, 2, 3, 4, 0, 0, 0, 0];
+
+    // Records the two 4-byte blocks of `piece`, the first from the honest peer.
+    fn receive(ban: &mut SmartBan, piece: u32, second: IpAddr) {
+        ban.on_block(piece, 0, 4, HONEST);
+        ban.on_block(piece, 4, 4, second);
+    }
+
+    fn good() -> Result<Vec<u8>, Error> {
+        Ok(GOOD.to_vec())
+    }
+
+    #[test]
+    fn verified_piece_without_failures_reads_nothing() {
+        let mut ban = SmartBan::default();
+        receive(&mut ban, 0, LIAR);
+        let culprits = ban
+            .piece_verified(0, || panic!("read a piece that never failed"))
+            .unwrap();
+        assert!(culprits.is_empty());
+    }
+
+    #[test]
+    fn shared_failures_strike_nobody() {
+        let mut ban = SmartBan::default();
+        for piece in 0..MAX_STRIKES * 2 {
+            receive(&mut ban, piece, LIAR);
+            assert!(ban.piece_failed(piece, &BAD).is_empty());
+        }
+        assert_eq!(ban.strikes(HONEST), 0);
+        assert_eq!(ban.strikes(LIAR), 0);
+
+        // attribution finds the culprit once the pieces pass
+        for piece in 0..MAX_STRIKES * 2 {
+            assert_eq!(ban.piece_verified(piece, good).unwrap(), vec![LIAR]);
+        }
+    }
+
+    #[test]
+    fn sole_sender_is_banned_after_too_many_strikes() {
+        let mut ban = SmartBan::default();
+        for piece in 0..MAX_STRIKES - 1 {
+            ban.on_block(piece, 0, 8, LIAR);
+            assert!(ban.piece_failed(piece, &BAD).is_empty());
+        }
+        assert_eq!(ban.strikes(LIAR), MAX_STRIKES - 1);
+
+        ban.on_block(MAX_STRIKES, 0, 8, LIAR);
+        assert_eq!(ban.piece_failed(MAX_STRIKES, &BAD), vec![LIAR]);
+    }
+
+    #[test]
+    fn passing_piece_bans_the_peer_that_sent_bad_data() {
+        let mut ban = SmartBan::default();
+        receive(&mut ban, 0, LIAR);
+        ban.piece_failed(0, &BAD);
+        // the retry comes from the honest peer alone
+        receive(&mut ban, 0, HONEST);
+        assert_eq!(ban.piece_verified(0, good).unwrap(), vec![LIAR]);
+        assert_eq!(ban.strikes(HONEST), 0);
+    }
+
+    #[test]
+    fn passing_piece_returns_strikes_for_intact_blocks() {
+        let mut ban = SmartBan::default();
+        // failed for some other reason than what the peer sent
+        receive(&mut ban, 0, HONEST);
+        ban.piece_failed(0, &GOOD);
+        assert_eq!(ban.strikes(HONEST), 1);
+
+        receive(&mut ban, 0, HONEST);
+        assert!(ban.piece_verified(0, good).unwrap().is_empty());
+        assert_eq!(ban.strikes(HONEST), 0);
+    }
+
+    #[test]
+    fn one_bad_block_outweighs_good_ones() {
+        let mut ban = SmartBan::default();
+        ban.on_block(0, 0, 4, LIAR);
+        ban.on_block(0, 4, 4, LIAR);
+        ban.piece_failed(0, &BAD);
+        assert_eq!(ban.strikes(LIAR), 1);
+        assert_eq!(ban.piece_verified(0, good).unwrap(), vec![LIAR]);
+        assert_eq!(ban.strikes(LIAR), 1);
+    }
+
+    #[test]
+    fn failure_by_a_banned_peer_strikes_nobody() {
+        let mut ban = SmartBan::default();
+        receive(&mut ban, 0, LIAR);
+        ban.piece_failed(0, &BAD);
+        assert_eq!(ban.piece_verified(0, good).unwrap(), vec![LIAR]);
+
+        // an unexplained failure the honest peer keeps its strike for
+        ban.on_block(1, 0, 8, HONEST);
+        ban.piece_failed(1, &BAD);
+        assert_eq!(ban.strikes(HONEST), 1);
+
+        // the banned peer sent this one alone
+        ban.on_block(2, 0, 8, LIAR);
+        assert!(ban.piece_failed(2, &BAD).is_empty());
+        assert_eq!(ban.strikes(LIAR), 0);
+
+        // and shared this one, which clears nobody when it passes
+        receive(&mut ban, 3, LIAR);
+        assert!(ban.piece_failed(3, &BAD).is_empty());
+        assert_eq!(ban.piece_verified(3, good).unwrap(), vec![LIAR]);
+        assert_eq!(ban.strikes(HONEST), 1);
+    }
+}
@@ -0,0 +1,579 @@
+use std::{
+    collections::HashSet,
+    future::Future,
+    net::SocketAddr,
+    ops::Range,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Error;
+use tokio::{
+    sync::{
+        mpsc::{self, Receiver, UnboundedReceiver},
+        watch, Mutex as AsyncMutex, MutexGuard as AsyncMutexGuard,
+    },
+    task::JoinSet,
+};
+
+use crate::listener::IncomingPeer;
+use crate::pex::PeerPool;
+use crate::ratelimit::RateLimits;
+use crate::storage::{resume::ResumeFile, Span, Storage};
+use crate::tcp::ConnectionOptions;
+use crate::torrent::{
+    client::Client,
+    events::{Event, Events},
+    picker::{PiecePicker, Priority},
+    smartban::SmartBan,
+    torrent::Torrent,
+};
+
+pub const DEFAULT_MAX_PEERS: usize = 30;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+// verified pieces waiting to be counted
+const VERIFIED_QUEUE: usize = 16;
+// how often progress is saved to the resume record while downloading
+const RESUME_INTERVAL: Duration = Duration::from_secs(10);
+// how often transfer totals are reported while downloading
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+// the least time between announces, whatever the tracker asks for and however short of peers
+// we are
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+
+type Announcing<'a> = Pin<Box<dyn Future<Output = Result<usize, Error>> + Send + 'a>>;
+
+// Downloads pieces from many peers at once, replacing peers that fail with new candidates
+// from the peer pool (tracker peers, plus whatever ut_pex turns up). The tracker is asked
+// again as often as it wants, and sooner when we run out of peers. With a listener in the
+// connection options, peers that connect to us are taken on too, up to the same limit.
+pub struct Swarm {
+    torrent: Torrent,
+    options: ConnectionOptions,
+    peer_pool: Arc<Mutex<PeerPool>>,
+    picker: Arc<Mutex<PiecePicker>>,
+    storage: Arc<Mutex<dyn Storage>>,
+    resume: Option<ResumeFile>,
+    events: Events,
+    smart_ban: Arc<Mutex<SmartBan>>,
+    // when we last announced and how long the tracker wants us to wait before the next one
+    tracker: Mutex<Option<(Instant, Duration)>>,
+    // peers the listener has handed us; taken by whichever `run` is going
+    incoming: Option<AsyncMutex<Receiver<IncomingPeer>>>,
+    stop: watch::Sender<bool>,
+    // set while hash-checking, and left set if the check was cut short, as the picker then
+    // knows nothing of the pieces it didn't reach
+    checking: AtomicBool,
+}
+
+impl Swarm {
+    pub fn new(
+        torrent: Torrent,
+        peers: Vec<SocketAddr>,
+        mut options: ConnectionOptions,
+        storage: Arc<Mutex<dyn Storage>>,
+    ) -> Self {
+        let mut picker = PiecePicker::new(&torrent);
+        picker.set_strategy(options.strategy.build());
+        // the torrent limit applies to this swarm alone
+        options.rate_limits = options.rate_limits.for_torrent();
+        let incoming = options
+            .listener
+            .as_ref()
+            .map(|listener| AsyncMutex::new(listener.add_torrent(torrent.get_info_hash())));
+        Self {
+            torrent,
+            options,
+            peer_pool: Arc::new(Mutex::new(PeerPool::new(peers))),
+            picker: Arc::new(Mutex::new(picker)),
+            storage,
+            resume: None,
+            events: Events::default(),
+            smart_ban: Arc::new(Mutex::new(SmartBan::default())),
+            tracker: Mutex::new(None),
+            incoming,
+            stop: watch::Sender::new(false),
+            checking: AtomicBool::new(false),
+        }
+    }
+
+    pub fn picker(&self) -> Arc<Mutex<PiecePicker>> {
+        self.picker.clone()
+    }
+
+    pub fn set_piece_priority(&self, piece: u32, priority: Priority) {
+        self.picker.lock().unwrap().set_priority(piece, priority);
+    }
+
+    // Gives each piece the priority of the files it covers; pieces only in skipped files
+    // aren't downloaded.
+    pub fn set_file_priorities(&self, priorities: &[Priority]) {
+        let pieces = self
+            .storage
+            .lock()
+            .unwrap()
+            .layout()
+            .piece_priorities(priorities);
+        let mut picker = self.picker.lock().unwrap();
+        for (piece, priority) in pieces.into_iter().enumerate() {
+            picker.set_priority(piece as u32, priority);
+        }
+    }
+
+    // Bandwidth limits for this swarm's connections, which can be changed while it runs.
+    pub fn rate_limits(&self) -> &RateLimits {
+        &self.options.rate_limits
+    }
+
+    pub fn peer_pool(&self) -> Arc<Mutex<PeerPool>> {
+        self.peer_pool.clone()
+    }
+
+    pub fn storage(&self) -> Arc<Mutex<dyn Storage>> {
+        self.storage.clone()
+    }
+
+    // Reports pieces, peers, transfer totals and announces on the returned channel.
+    pub fn subscribe(&mut self) -> UnboundedReceiver<Event> {
+        self.events.subscribe()
+    }
+
+    // Asks the torrent's tracker for peers and adds them to the pool. Returns how many it
+    // gave us.
+    pub async fn announce(&self) -> Result<usize, Error> {
+        let result = self
+            .torrent
+            .get_peers_with_interval(self.options.port())
+            .await
+            .map_err(|e| e.to_string());
+        let interval = match &result {
+            Ok((_, interval)) => (*interval).max(MIN_ANNOUNCE_INTERVAL),
+            Err(_) => MIN_ANNOUNCE_INTERVAL,
+        };
+        *self.tracker.lock().unwrap() = Some((Instant::now(), interval));
+        self.events.send(Event::Announce {
+            tracker: self.torrent.announce.clone(),
+            result: result
+                .as_ref()
+                .map(|(peers, _)| peers.len())
+                .map_err(Clone::clone),
+        });
+        let (peers, _) = result.map_err(|e| anyhow::anyhow!("Announce failed: {}", e))?;
+        let count = peers.len();
+        self.peer_pool.lock().unwrap().add_peers(peers);
+        Ok(count)
+    }
+
+    // Makes the download in progress, and any started later, fail once its peers have stopped
+    // writing to storage. Progress is saved to the resume record as for any other failure.
+    pub fn stop(&self) {
+        self.stop.send_replace(true);
+    }
+
+    // Keeps progress in a resume record so an interrupted `download` fetches only what's
+    // missing when it's run again.
+    pub fn set_resume(&mut self, resume: ResumeFile) {
+        self.resume = Some(resume);
+    }
+
+    // Peers write blocks straight into storage; this returns once every piece that isn't
+    // skipped is verified.
+    pub async fn download(&self) -> Result<(), Error> {
+        if let Some(resume) = &self.resume {
+            self.restore(resume).await?;
+        }
+        self.storage.lock().unwrap().allocate()?;
+        self.fetch_missing().await
+    }
+
+    // Hash-checks what's already in storage and re-fetches only the pieces that are missing
+    // or corrupt, writing them in place. Any resume record is ignored.
+    pub async fn repair(&self) -> Result<(), Error> {
+        self.storage.lock().unwrap().allocate()?;
+        let good = self.hash_check(|_| true).await?;
+        self.events.send(Event::Checked {
+            good,
+            total: self.torrent.get_piece_count(),
+        });
+        self.fetch_missing().await
+    }
+
+    async fn fetch_missing(&self) -> Result<(), Error> {
+        let pieces = {
+            let picker = self.picker.lock().unwrap();
+            (0..self.torrent.get_piece_count() as u32)
+                .filter(|piece| picker.priority(*piece) != Priority::Skip)
+                .collect()
+        };
+        if let Err(e) = self.run(pieces).await {
+            self.save_resume()?;
+            return Err(e);
+        }
+        self.storage.lock().unwrap().flush()?;
+        match &self.resume {
+            Some(resume) => resume.remove(),
+            None => Ok(()),
+        }
+    }
+
+    // Works out which pieces we already have: from the resume record if there is one,
+    // otherwise by hash-checking whatever data is on disk.
+    async fn restore(&self, resume: &ResumeFile) -> Result<(), Error> {
+        if let Some((pieces, partial)) = resume.load() {
+            let mut picker = self.picker.lock().unwrap();
+            for piece in pieces.iter() {
+                picker.piece_verified(piece as u32);
+            }
+            for (piece, blocks) in partial {
+                picker.restore_partial(piece, &blocks);
+            }
+            self.events.send(Event::Resumed {
+                completed: picker.have().count(),
+                total: self.torrent.get_piece_count(),
+            });
+            return Ok(());
+        }
+
+        let existing: Vec<u64> = resume
+            .lengths()
+            .into_iter()
+            .map(|length| length.unwrap_or(0))
+            .collect();
+        if existing.iter().all(|length| *length == 0) {
+            return Ok(());
+        }
+        let good = self
+            .hash_check(move |span| {
+                span.file_offset + span.range.len() as u64 <= existing[span.file]
+            })
+            .await?;
+        self.events.send(Event::Checked {
+            good,
+            total: self.torrent.get_piece_count(),
+        });
+        Ok(())
+    }
+
+    // Marks every piece whose stored data matches its hash as done, and returns how many
+    // did. Pieces with a span that isn't `on_disk` are skipped without being read. Runs off
+    // the async threads, locking the picker and storage for a piece at a time, and gives up
+    // once the swarm is stopped.
+    async fn hash_check(
+        &self,
+        on_disk: impl Fn(&Span) -> bool + Send + 'static,
+    ) -> Result<usize, Error> {
+        let torrent = self.torrent.clone();
+        let picker = self.picker.clone();
+        let storage = self.storage.clone();
+        let stop = self.stop.subscribe();
+        self.checking.store(true, Ordering::SeqCst);
+        let good = tokio::task::spawn_blocking(move || {
+            for piece in 0..torrent.get_piece_count() as u32 {
+                if *stop.borrow() {
+                    return Err(anyhow::anyhow!("Stopped while checking"));
+                }
+                if picker.lock().unwrap().priority(piece) == Priority::Skip {
+                    continue;
+                }
+                let verified = {
+                    let mut storage = storage.lock().unwrap();
+                    let size = storage.layout().piece_size(piece);
+                    if !storage
+                        .layout()
+                        .spans(piece, 0, size as u64)?
+                        .iter()
+                        .all(&on_disk)
+                    {
+                        continue;
+                    }
+                    storage.verify(piece, &torrent.get_piece_hash(piece as usize))?
+                };
+                if verified {
+                    picker.lock().unwrap().piece_verified(piece);
+                }
+            }
+            Ok(picker.lock().unwrap().have().count())
+        })
+        .await??;
+        self.checking.store(false, Ordering::SeqCst);
+        Ok(good)
+    }
+
+    // Flushes storage and records progress so far. Does nothing without a resume record, or
+    // while a hash check is unfinished, leaving whatever record there was to be checked again.
+    pub fn save_resume(&self) -> Result<(), Error> {
+        let Some(resume) = &self.resume else {
+            return Ok(());
+        };
+        if self.checking.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        // peers write a block before the picker lets go of it, so everything taken from the
+        // picker here is in storage by the time it's flushed
+        let (have, partial) = {
+            let picker = self.picker.lock().unwrap();
+            (picker.have().clone(), picker.partial_blocks())
+        };
+        self.storage.lock().unwrap().flush()?;
+        resume.save(&have, partial)
+    }
+
+    // Downloads and verifies just the pieces covering `range` of the torrent's content, and
+    // returns those bytes.
+    pub async fn download_range(&self, range: Range<u64>) -> Result<Vec<u8>, Error> {
+        let pieces = self.storage.lock().unwrap().layout().pieces_in(&range)?;
+        self.download_pieces(pieces).await?;
+        self.storage.lock().unwrap().read_range(range)
+    }
+
+    // Downloads just the given pieces; every other piece is skipped.
+    pub async fn download_pieces(&self, pieces: Vec<u32>) -> Result<(), Error> {
+        self.run(pieces).await?;
+        self.storage.lock().unwrap().flush()
+    }
+
+    async fn run(&self, pieces: Vec<u32>) -> Result<(), Error> {
+        let wanted: HashSet<u32> = pieces.into_iter().collect();
+        {
+            let mut picker = self.picker.lock().unwrap();
+            for piece in 0..self.torrent.get_piece_count() as u32 {
+                if !wanted.contains(&piece) {
+                    picker.set_priority(piece, Priority::Skip);
+                } else if picker.priority(piece) == Priority::Skip {
+                    picker.set_priority(piece, Priority::Normal);
+                }
+            }
+        }
+
+        // bounded so peers stall rather than pile up verified pieces when storage is slow
+        let (tx, mut rx) = mpsc::channel(VERIFIED_QUEUE);
+        let mut tasks = JoinSet::new();
+        // peers that connected to us, which have their own allowance of `max_peers`
+        let mut incoming_tasks = JoinSet::new();
+        let mut incoming = self
+            .incoming
+            .as_ref()
+            .and_then(|incoming| incoming.try_lock().ok());
+        let mut remaining = {
+            let picker = self.picker.lock().unwrap();
+            wanted
+                .iter()
+                .filter(|piece| !picker.have().has(**piece as usize))
+                .count()
+        };
+        let mut last_save = Instant::now();
+        let total = wanted.len();
+        self.events.send(Event::Started {
+            completed: total - remaining,
+            total,
+        });
+        let mut progress = tokio::time::interval(PROGRESS_INTERVAL);
+        let mut announcing: Option<Announcing> = None;
+        let mut stop = self.stop.subscribe();
+
+        let result = loop {
+            if remaining == 0 {
+                break Ok(());
+            }
+            while tasks.len() < self.options.max_peers {
+                let Some(peer) = self.peer_pool.lock().unwrap().next_candidate() else {
+                    break;
+                };
+                let client = self.client();
+                let tx = tx.clone();
+                let events = self.events.clone();
+                tasks.spawn(async move { run_peer(client, peer, tx, events).await });
+            }
+            let out_of_peers = tasks.is_empty() && incoming_tasks.is_empty();
+            let next_announce = match announcing {
+                Some(_) => None,
+                None => self.next_announce(out_of_peers),
+            };
+
+            tokio::select! {
+                // a peer sends its last piece before exiting, so take pieces first or we could
+                // run out of peers with that piece still queued
+                biased;
+                _ = stop.wait_for(|stop| *stop) => {
+                    break Err(anyhow::anyhow!("Stopped with {} pieces left", remaining));
+                }
+                Some(piece) = rx.recv() => {
+                    remaining -= 1;
+                    self.events.send(Event::PieceCompleted {
+                        piece,
+                        completed: total - remaining,
+                        total,
+                    });
+                    if last_save.elapsed() >= RESUME_INTERVAL {
+                        if let Err(e) = self.save_resume() {
+                            break Err(e);
+                        }
+                        last_save = Instant::now();
+                    }
+                }
+                // peers report how they ended with a disconnect event
+                Some(_) = tasks.join_next() => {}
+                Some(_) = incoming_tasks.join_next() => {}
+                Some(incoming) = next_incoming(&mut incoming) => {
+                    let banned = self.peer_pool.lock().unwrap().is_banned(incoming.peer.ip());
+                    // dropping the peer closes its connection
+                    if banned || incoming_tasks.len() >= self.options.max_peers {
+                        continue;
+                    }
+                    let client = self.client();
+                    let tx = tx.clone();
+                    let events = self.events.clone();
+                    incoming_tasks.spawn(async move {
+                        run_incoming_peer(client, incoming, tx, events).await
+                    });
+                }
+                Some(result) = finish_announce(&mut announcing) => {
+                    announcing = None;
+                    if let Err(e) = result {
+                        if tasks.is_empty() && incoming_tasks.is_empty() {
+                            break Err(anyhow::anyhow!(
+                                "Ran out of peers with {} pieces left: {}",
+                                remaining,
+                                e
+                            ));
+                        }
+                    }
+                }
+                _ = sleep_until(next_announce) => announcing = Some(Box::pin(self.announce())),
+                _ = progress.tick() => self.events.send_transferred(),
+            }
+        };
+
+        // waits for the peers to stop, so nothing is still being written when progress is saved
+        tasks.shutdown().await;
+        incoming_tasks.shutdown().await;
+        self.events.send_transferred();
+        result
+    }
+
+    // When to ask the tracker for more peers: as often as it wants, or as soon as we're allowed
+    // once we've run out. A swarm started from a list of peers only announces once it runs out.
+    fn next_announce(&self, out_of_peers: bool) -> Option<Instant> {
+        match *self.tracker.lock().unwrap() {
+            Some((at, _)) if out_of_peers => Some(at + MIN_ANNOUNCE_INTERVAL),
+            Some((at, interval)) => Some(at + interval),
+            None if out_of_peers => Some(Instant::now()),
+            None => None,
+        }
+    }
+
+    fn client(&self) -> Client {
+        let mut client = Client::new(self.torrent.clone());
+        client.set_connection_options(self.options.clone());
+        client.set_peer_pool(self.peer_pool.clone());
+        client.set_picker(self.picker.clone());
+        client.set_storage(self.storage.clone());
+        client.set_events(self.events.clone());
+        client.set_smart_ban(self.smart_ban.clone());
+        client
+    }
+}
+
+async fn run_peer(
+    mut client: Client,
+    peer: SocketAddr,
+    tx: mpsc::Sender<u32>,
+    events: Events,
+) -> Result<(), Error> {
+    let mut connection = Connection {
+        peer,
+        events,
+        error: None,
+    };
+    let result = async {
+        tokio::time::timeout(CONNECT_TIMEOUT, client.handshake(peer))
+            .await
+            .map_err(|_| anyhow::anyhow!("Timed out connecting"))??;
+        connection.events.send(Event::PeerConnected(peer));
+        client.download(&tx).await
+    }
+    .await;
+    if let Err(e) = &result {
+        connection.error = Some(e.to_string());
+    }
+    result
+}
+
+// Downloads from a peer that connected to us, then keeps uploading to it for as long as it
+// wants anything.
+async fn run_incoming_peer(
+    mut client: Client,
+    incoming: IncomingPeer,
+    tx: mpsc::Sender<u32>,
+    events: Events,
+) -> Result<(), Error> {
+    let IncomingPeer {
+        peer,
+        stream,
+        handshake,
+        permit: _permit,
+    } = incoming;
+    let mut connection = Connection {
+        peer,
+        events,
+        error: None,
+    };
+    let result = async {
+        tokio::time::timeout(CONNECT_TIMEOUT, client.accept(peer, stream, handshake))
+            .await
+            .map_err(|_| anyhow::anyhow!("Timed out answering handshake"))??;
+        connection.events.send(Event::PeerConnected(peer));
+        client.download(&tx).await?;
+        client.serve().await
+    }
+    .await;
+    if let Err(e) = &result {
+        connection.error = Some(e.to_string());
+    }
+    result
+}
+
+async fn finish_announce(announcing: &mut Option<Announcing<'_>>) -> Option<Result<usize, Error>> {
+    match announcing {
+        Some(announcing) => Some(announcing.await),
+        None => std::future::pending().await,
+    }
+}
+
+async fn sleep_until(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn next_incoming(
+    incoming: &mut Option<AsyncMutexGuard<'_, Receiver<IncomingPeer>>>,
+) -> Option<IncomingPeer> {
+    match incoming {
+        Some(incoming) => incoming.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Reports a peer's disconnect however its task ends, including being aborted once the
+// download is done.
+struct Connection {
+    peer: SocketAddr,
+    events: Events,
+    error: Option<String>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.events.send(Event::PeerDisconnected {
+            peer: self.peer,
+            error: self.error.take(),
+        });
+    }
+}
@@ -3,6 +3,10 @@ use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::bencode;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Torrent {
     pub announce: String,
@@ -12,15 +16,32 @@ pub struct Torrent {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Info {
     pub name: String,
-    pub length: u32,
+    // single-file torrents have `length`, multi-file torrents have `files`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<FileInfo>>,
     pub pieces: ByteBuf,
     #[serde(rename = "piece length")]
     pub piece_length: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+    // the dict exactly as it was bencoded, when we have it; serializing the fields above
+    // drops keys we don't model, which changes the info hash
+    #[serde(skip)]
+    pub bytes: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FileInfo {
+    pub length: u64,
+    pub path: Vec<String>,
 }
 
 impl Info {
     pub fn from_bytes(bytes: &[u8]) -> Self {
-        let info = serde_bencode::from_bytes(bytes).unwrap();
+        let mut info: Info = serde_bencode::from_bytes(bytes).unwrap();
+        info.bytes = Some(bytes.to_vec());
         info
     }
 }
@@ -38,28 +59,51 @@ impl Torrent {
 
     pub fn from(file_name: &PathBuf) -> Self {
         let file = std::fs::read(file_name).expect("Failed to read the file");
-        let torrent: Torrent = serde_bencode::from_bytes(&file).unwrap();
+        let mut torrent: Torrent = serde_bencode::from_bytes(&file).unwrap();
+        torrent.info.bytes = bencode::dict_value(&file, b"info")
+            .ok()
+            .flatten()
+            .map(<[u8]>::to_vec);
         torrent
     }
 
+    pub fn get_info_bytes(&self) -> Vec<u8> {
+        match &self.info.bytes {
+            Some(bytes) => bytes.clone(),
+            None => serde_bencode::to_bytes(&self.info).unwrap(),
+        }
+    }
+
     pub fn get_info_hash(&self) -> [u8; 20] {
         let mut hasher = Sha1::new();
-        let info_bytes = serde_bencode::to_bytes(&self.info).unwrap();
+        let info_bytes = self.get_info_bytes();
         hasher.update(&info_bytes);
         let hash = hasher.finalize();
-        hash.try_into().expect("Failed to convert hash to array")
+        hash.into()
     }
 
-    pub async fn get_peers(&self) -> Result<Vec<SocketAddr>, Box<dyn std::error::Error>> {
+    // `port` is where we accept peer connections.
+    pub async fn get_peers(
+        &self,
+        port: u16,
+    ) -> Result<Vec<SocketAddr>, Box<dyn std::error::Error>> {
+        Ok(self.get_peers_with_interval(port).await?.0)
+    }
+
+    // Also returns how long the tracker wants us to wait before announcing again.
+    pub async fn get_peers_with_interval(
+        &self,
+        port: u16,
+    ) -> Result<(Vec<SocketAddr>, Duration), Box<dyn std::error::Error>> {
         let info_hash = self.get_info_hash();
         let url_encoded_info_hash = urlencoding::encode_binary(&info_hash).to_string();
 
         let url_params = serde_json::json!({
             "peer_id": "01012323454567678989",
-            "port": 6881,
+            "port": port,
             "uploaded": 1,
             "downloaded": 1,
-            "left": self.info.length,
+            "left": self.total_length(),
             "compact": 1
         });
 
@@ -69,7 +113,6 @@ impl Torrent {
             "{}?{}&info_hash={}",
             self.announce, encoded_url_params, url_encoded_info_hash
         );
-        dbg!(&url);
 
         // Send request to tracker and parse response
         let tracker_response = reqwest::get(url.as_str()).await?;
@@ -89,7 +132,11 @@ impl Torrent {
             }
             i += 6;
         }
-        Ok(peers)
+        Ok((peers, Duration::from_secs(tracker_response.interval as u64)))
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(1)
     }
 
     pub fn get_piece_hashes(&self) -> Vec<String> {
@@ -106,9 +153,39 @@ impl Torrent {
         self.info.pieces.len() / 20
     }
 
+    pub fn total_length(&self) -> u64 {
+        match &self.info.files {
+            Some(files) => files.iter().map(|file| file.length).sum(),
+            None => self.info.length.unwrap_or(0),
+        }
+    }
+
+    // Files in torrent order with their paths relative to the download root. A single-file
+    // torrent is one file with an empty relative path.
+    pub fn get_files(&self) -> Vec<(PathBuf, u64)> {
+        match &self.info.files {
+            Some(files) => files
+                .iter()
+                .map(|file| (file.path.iter().collect(), file.length))
+                .collect(),
+            None => vec![(PathBuf::new(), self.total_length())],
+        }
+    }
+
+    // Names used to pick files: the path within the torrent, or the name of a single file.
+    pub fn get_file_names(&self) -> Vec<String> {
+        match &self.info.files {
+            Some(files) => files.iter().map(|file| file.path.join("/")).collect(),
+            None => vec![self.info.name.clone()],
+        }
+    }
+
     pub fn get_piece_length(&self, piece_index: usize) -> u32 {
-        if piece_index == self.get_piece_count() - 1 {
-            self.info.length % self.info.piece_length
+        let piece_length = self.info.piece_length as u64;
+        if piece_index == self.get_piece_count() - 1
+            && !self.total_length().is_multiple_of(piece_length)
+        {
+            (self.total_length() % piece_length) as u32
         } else {
             self.info.piece_length
         }
@@ -125,12 +202,52 @@ impl Torrent {
         let hashes = self.get_piece_hashes();
 
         println!("Tracker URL: {}", tracker_url);
-        println!("Length: {}", self.info.length);
+        println!("Length: {}", self.total_length());
         println!("Info Hash: {}", info_hash_str);
         println!("Piece Length: {}", self.info.piece_length);
         println!("Piece Hashes:");
         for hash in hashes {
             println!("{}", hash);
         }
+        if let Some(files) = &self.info.files {
+            println!("Files:");
+            for (index, (name, file)) in self.get_file_names().iter().zip(files).enumerate() {
+                println!("{}: {} ({} bytes)", index, name, file.length);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an info dict with a key `Info` doesn't model
+    const INFO: &[u8] =
+        b"d6:lengthi5e4:name1:x12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa6:source3:abce";
+
+    #[test]
+    fn keeps_the_info_dict_from_a_torrent_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("x.torrent");
+        let file = [&b"d8:announce3:url4:info"[..], INFO, b"e"].concat();
+        std::fs::write(&path, file).unwrap();
+
+        let torrent = Torrent::from(&path);
+        assert_eq!(torrent.get_info_bytes(), INFO);
+        assert_eq!(
+            torrent.get_info_hash(),
+            <[u8; 20]>::from(Sha1::digest(INFO))
+        );
+    }
+
+    #[test]
+    fn keeps_the_info_dict_from_metadata() {
+        let torrent = Torrent::new("url".to_string(), Info::from_bytes(INFO));
+        assert_eq!(torrent.get_info_bytes(), INFO);
+        assert_eq!(
+            torrent.get_info_hash(),
+            <[u8; 20]>::from(Sha1::digest(INFO))
+        );
     }
 }
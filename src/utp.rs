@@ -0,0 +1,1086 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Error;
+use rand::{rng, Rng};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{ToSocketAddrs, UdpSocket},
+    sync::{mpsc, OnceCell},
+    task::JoinHandle,
+};
+
+const ST_DATA: u8 = 0;
+const ST_FIN: u8 = 1;
+const ST_STATE: u8 = 2;
+const ST_RESET: u8 = 3;
+const ST_SYN: u8 = 4;
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 20;
+const EXT_SELECTIVE_ACK: u8 = 1;
+// selective ack bitmask covers this many packets past ack_nr + 1
+const SACK_BITS: u16 = 32;
+
+const PACKET_SIZE: usize = 1400;
+const MIN_WINDOW: f64 = (2 * PACKET_SIZE) as f64;
+const MAX_WINDOW: f64 = (1 << 20) as f64;
+const RECV_WINDOW: usize = 1 << 20;
+// furthest ahead of ack_nr an out-of-order packet is buffered
+const REORDER_LIMIT: u16 = 1024;
+
+// LEDBAT parameters
+const TARGET_DELAY_US: f64 = 100_000.0;
+const MAX_CWND_INCREASE_PER_RTT: f64 = 3000.0;
+const BASE_DELAY_WINDOW: Duration = Duration::from_secs(60);
+
+const TICK: Duration = Duration::from_millis(50);
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(30);
+const MAX_SYN_TIMEOUTS: u32 = 3;
+const MAX_TIMEOUTS: u32 = 8;
+const ACCEPT_BACKLOG: usize = 32;
+
+fn now_micros() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u32
+}
+
+// true when `a` comes after `b` in wrapping sequence-number space
+fn seq_after(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) > 0
+}
+
+#[derive(Debug, Clone)]
+struct Packet {
+    kind: u8,
+    connection_id: u16,
+    timestamp: u32,
+    timestamp_diff: u32,
+    wnd_size: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+    selective_ack: Option<Vec<u8>>,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        bytes.push((self.kind << 4) | VERSION);
+        bytes.push(if self.selective_ack.is_some() {
+            EXT_SELECTIVE_ACK
+        } else {
+            0
+        });
+        bytes.extend_from_slice(&self.connection_id.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp_diff.to_be_bytes());
+        bytes.extend_from_slice(&self.wnd_size.to_be_bytes());
+        bytes.extend_from_slice(&self.seq_nr.to_be_bytes());
+        bytes.extend_from_slice(&self.ack_nr.to_be_bytes());
+        if let Some(mask) = &self.selective_ack {
+            bytes.push(0);
+            bytes.push(mask.len() as u8);
+            bytes.extend_from_slice(mask);
+        }
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN || bytes[0] & 0x0f != VERSION || bytes[0] >> 4 > ST_SYN {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes(bytes[i..i + 4].try_into().unwrap());
+
+        let mut selective_ack = None;
+        let mut extension = bytes[1];
+        let mut pos = HEADER_LEN;
+        while extension != 0 {
+            let next = *bytes.get(pos)?;
+            let len = *bytes.get(pos + 1)? as usize;
+            let data = bytes.get(pos + 2..pos + 2 + len)?;
+            if extension == EXT_SELECTIVE_ACK {
+                selective_ack = Some(data.to_vec());
+            }
+            extension = next;
+            pos += 2 + len;
+        }
+
+        Some(Self {
+            kind: bytes[0] >> 4,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            selective_ack,
+            payload: bytes[pos..].to_vec(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    SynSent,
+    Connected,
+}
+
+struct Outgoing {
+    seq_nr: u16,
+    kind: u8,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32,
+    acked: bool,
+}
+
+struct Connection {
+    socket: Arc<UdpSocket>,
+    remote: SocketAddr,
+    state: State,
+    send_id: u16,
+    seq_nr: u16,
+    ack_nr: u16,
+    reply_micro: u32,
+
+    // receive side
+    out_of_order: HashMap<u16, (u8, Vec<u8>)>,
+    out_of_order_bytes: usize,
+    read_buf: VecDeque<u8>,
+    fin_seq: Option<u16>,
+    eof: bool,
+
+    // send side
+    in_flight: VecDeque<Outgoing>,
+    bytes_in_flight: usize,
+    max_window: f64,
+    peer_window: usize,
+    fin_sent: bool,
+    last_ack_nr: u16,
+    duplicate_acks: u32,
+    // packets selectively acked past the oldest unacked one since it was last resent
+    sacked_past_hole: u32,
+    last_loss: Option<Instant>,
+    last_fast_resend: Option<Instant>,
+
+    // timing and congestion control
+    rtt_us: f64,
+    rtt_var_us: f64,
+    rto: Duration,
+    timeouts: u32,
+    base_delays: VecDeque<(Instant, u32)>,
+
+    error: Option<io::ErrorKind>,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    connect_waker: Option<Waker>,
+}
+
+impl Connection {
+    fn new(socket: Arc<UdpSocket>, remote: SocketAddr, state: State, send_id: u16) -> Self {
+        Self {
+            socket,
+            remote,
+            state,
+            send_id,
+            seq_nr: 1,
+            ack_nr: 0,
+            reply_micro: 0,
+            out_of_order: HashMap::new(),
+            out_of_order_bytes: 0,
+            read_buf: VecDeque::new(),
+            fin_seq: None,
+            eof: false,
+            in_flight: VecDeque::new(),
+            bytes_in_flight: 0,
+            max_window: MIN_WINDOW,
+            peer_window: RECV_WINDOW,
+            fin_sent: false,
+            last_ack_nr: 0,
+            duplicate_acks: 0,
+            sacked_past_hole: 0,
+            last_loss: None,
+            last_fast_resend: None,
+            rtt_us: 0.0,
+            rtt_var_us: 0.0,
+            rto: INITIAL_RTO,
+            timeouts: 0,
+            base_delays: VecDeque::new(),
+            error: None,
+            read_waker: None,
+            write_waker: None,
+            connect_waker: None,
+        }
+    }
+
+    fn packet(&self, kind: u8, seq_nr: u16, payload: Vec<u8>) -> Packet {
+        Packet {
+            kind,
+            connection_id: if kind == ST_SYN {
+                self.send_id.wrapping_sub(1)
+            } else {
+                self.send_id
+            },
+            timestamp: now_micros(),
+            timestamp_diff: self.reply_micro,
+            wnd_size: self.receive_window() as u32,
+            seq_nr,
+            ack_nr: self.ack_nr,
+            selective_ack: self.selective_ack(),
+            payload,
+        }
+    }
+
+    // Room left for data we've received but the reader hasn't taken yet.
+    fn receive_window(&self) -> usize {
+        RECV_WINDOW.saturating_sub(self.read_buf.len() + self.out_of_order_bytes)
+    }
+
+    // Until the first sample, the initial timeout stands in for the round trip.
+    fn rtt(&self) -> Duration {
+        if self.rtt_us == 0.0 {
+            self.rto
+        } else {
+            Duration::from_micros(self.rtt_us as u64)
+        }
+    }
+
+    fn selective_ack(&self) -> Option<Vec<u8>> {
+        if self.out_of_order.is_empty() {
+            return None;
+        }
+        let mut mask = vec![0u8; SACK_BITS as usize / 8];
+        for i in 0..SACK_BITS {
+            if self
+                .out_of_order
+                .contains_key(&self.ack_nr.wrapping_add(2 + i))
+            {
+                mask[i as usize / 8] |= 1 << (i % 8);
+            }
+        }
+        Some(mask)
+    }
+
+    fn transmit(&self, packet: &Packet) {
+        // UDP is lossy anyway; a dropped send is recovered by retransmission
+        let _ = self.socket.try_send_to(&packet.to_bytes(), self.remote);
+    }
+
+    fn send_state(&self) {
+        self.transmit(&self.packet(ST_STATE, self.seq_nr, Vec::new()));
+    }
+
+    fn send_reliable(&mut self, kind: u8, payload: Vec<u8>) {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.transmit(&self.packet(kind, seq_nr, payload.clone()));
+        self.bytes_in_flight += payload.len();
+        self.in_flight.push_back(Outgoing {
+            seq_nr,
+            kind,
+            payload,
+            sent_at: Instant::now(),
+            transmissions: 1,
+            acked: false,
+        });
+    }
+
+    fn resend_oldest(&mut self) {
+        let Some(index) = self.in_flight.iter().position(|p| !p.acked) else {
+            return;
+        };
+        let (kind, seq_nr, payload) = {
+            let outgoing = &mut self.in_flight[index];
+            outgoing.sent_at = Instant::now();
+            outgoing.transmissions += 1;
+            (outgoing.kind, outgoing.seq_nr, outgoing.payload.clone())
+        };
+        self.transmit(&self.packet(kind, seq_nr, payload));
+    }
+
+    fn window_available(&self) -> usize {
+        let window = (self.max_window as usize).min(self.peer_window);
+        window.saturating_sub(self.bytes_in_flight)
+    }
+
+    fn fail(&mut self, kind: io::ErrorKind) {
+        self.error = Some(kind);
+        self.wake_all();
+    }
+
+    fn wake_all(&mut self) {
+        for waker in [
+            self.read_waker.take(),
+            self.write_waker.take(),
+            self.connect_waker.take(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            waker.wake();
+        }
+    }
+
+    // Done once there is nothing left to deliver or retransmit.
+    fn is_finished(&self) -> bool {
+        self.error.is_some() || (self.fin_sent && self.in_flight.is_empty() && self.eof)
+    }
+
+    fn on_packet(&mut self, packet: Packet) {
+        if packet.kind == ST_RESET {
+            self.fail(io::ErrorKind::ConnectionReset);
+            return;
+        }
+
+        self.reply_micro = now_micros().wrapping_sub(packet.timestamp);
+        self.peer_window = packet.wnd_size as usize;
+
+        if self.state == State::SynSent {
+            if packet.kind != ST_STATE {
+                return;
+            }
+            self.state = State::Connected;
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            if let Some(waker) = self.connect_waker.take() {
+                waker.wake();
+            }
+        }
+
+        self.on_ack(&packet);
+
+        match packet.kind {
+            ST_DATA | ST_FIN => {
+                self.on_data(packet);
+                self.send_state();
+            }
+            ST_SYN => self.send_state(),
+            _ => {}
+        }
+    }
+
+    fn on_ack(&mut self, packet: &Packet) {
+        let now = Instant::now();
+        let mut bytes_acked = 0;
+        let mut rtt_samples = Vec::new();
+
+        // true only the first time a packet is acked, so repeated SACKs don't count it again
+        let mut acked = |outgoing: &mut Outgoing| {
+            if outgoing.acked {
+                return false;
+            }
+            outgoing.acked = true;
+            bytes_acked += outgoing.payload.len();
+            if outgoing.transmissions == 1 {
+                rtt_samples.push(now.duration_since(outgoing.sent_at));
+            }
+            true
+        };
+        for outgoing in self.in_flight.iter_mut() {
+            if !seq_after(outgoing.seq_nr, packet.ack_nr) {
+                acked(outgoing);
+            }
+        }
+        if packet.ack_nr != self.last_ack_nr {
+            self.sacked_past_hole = 0;
+        }
+        if let Some(mask) = &packet.selective_ack {
+            for outgoing in self.in_flight.iter_mut() {
+                let offset = outgoing.seq_nr.wrapping_sub(packet.ack_nr.wrapping_add(2)) as usize;
+                if offset < mask.len() * 8
+                    && mask[offset / 8] & (1 << (offset % 8)) != 0
+                    && acked(outgoing)
+                {
+                    self.sacked_past_hole += 1;
+                }
+            }
+        }
+
+        while self.in_flight.front().is_some_and(|p| p.acked) {
+            self.in_flight.pop_front();
+        }
+        self.bytes_in_flight = self
+            .in_flight
+            .iter()
+            .filter(|p| !p.acked)
+            .map(|p| p.payload.len())
+            .sum();
+
+        for sample in rtt_samples {
+            self.update_rtt(sample);
+        }
+
+        let has_unacked = self.in_flight.iter().any(|p| !p.acked);
+        if packet.kind == ST_STATE && packet.ack_nr == self.last_ack_nr && has_unacked {
+            self.duplicate_acks += 1;
+        } else {
+            self.duplicate_acks = 0;
+        }
+        self.last_ack_nr = packet.ack_nr;
+
+        // three duplicate acks, or three packets selectively acked past a hole, means loss;
+        // the resend takes a round trip to show up in acks, so only resend once per round trip
+        let rtt = self.rtt();
+        let recently_resent = self
+            .last_fast_resend
+            .is_some_and(|at| now.duration_since(at) < rtt);
+        if has_unacked
+            && !recently_resent
+            && (self.duplicate_acks >= 3 || self.sacked_past_hole >= 3)
+        {
+            self.duplicate_acks = 0;
+            self.sacked_past_hole = 0;
+            self.last_fast_resend = Some(now);
+            self.on_loss(now);
+            self.resend_oldest();
+        }
+
+        if bytes_acked > 0 {
+            self.timeouts = 0;
+            if packet.timestamp_diff != 0 {
+                self.update_window(bytes_acked, packet.timestamp_diff, now);
+            }
+            if let Some(waker) = self.write_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        let sample = sample.as_micros() as f64;
+        if self.rtt_us == 0.0 {
+            self.rtt_us = sample;
+            self.rtt_var_us = sample / 2.0;
+        } else {
+            let delta = self.rtt_us - sample;
+            self.rtt_var_us += (delta.abs() - self.rtt_var_us) / 4.0;
+            self.rtt_us += (sample - self.rtt_us) / 8.0;
+        }
+        let rto = Duration::from_micros((self.rtt_us + 4.0 * self.rtt_var_us) as u64);
+        self.rto = rto.clamp(MIN_RTO, MAX_RTO);
+    }
+
+    // LEDBAT: grow the window while our queuing delay is under target, shrink it above.
+    fn update_window(&mut self, bytes_acked: usize, delay_sample: u32, now: Instant) {
+        while self
+            .base_delays
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > 2 * BASE_DELAY_WINDOW)
+        {
+            self.base_delays.pop_front();
+        }
+        match self.base_delays.back_mut() {
+            Some((at, min)) if now.duration_since(*at) < BASE_DELAY_WINDOW => {
+                *min = (*min).min(delay_sample);
+            }
+            _ => self.base_delays.push_back((now, delay_sample)),
+        }
+        let base_delay = self.base_delays.iter().map(|(_, d)| *d).min().unwrap();
+        let our_delay = delay_sample.wrapping_sub(base_delay) as f64;
+
+        let off_target = (TARGET_DELAY_US - our_delay) / TARGET_DELAY_US;
+        let bytes_acked = bytes_acked as f64;
+        let window_factor = bytes_acked.min(self.max_window) / self.max_window.max(bytes_acked);
+        let gain = MAX_CWND_INCREASE_PER_RTT * off_target * window_factor;
+        self.max_window = (self.max_window + gain).clamp(MIN_WINDOW, MAX_WINDOW);
+    }
+
+    fn on_loss(&mut self, now: Instant) {
+        let rtt = self.rtt();
+        if self
+            .last_loss
+            .is_some_and(|last_loss| now.duration_since(last_loss) < rtt)
+        {
+            return;
+        }
+        self.last_loss = Some(now);
+        self.max_window = (self.max_window / 2.0).max(MIN_WINDOW);
+    }
+
+    fn on_data(&mut self, packet: Packet) {
+        // data past the window we advertised is dropped unacked, and the peer resends it once the
+        // reader has made room. Out-of-order data we've already selectively acked can't be
+        // dropped, so it mustn't hold up the packet that fills the hole either.
+        let expected = self.ack_nr.wrapping_add(1);
+        if packet.seq_nr != expected {
+            if packet.payload.len() <= self.receive_window()
+                && seq_after(packet.seq_nr, expected)
+                && packet.seq_nr.wrapping_sub(expected) < REORDER_LIMIT
+                && !self.out_of_order.contains_key(&packet.seq_nr)
+            {
+                self.out_of_order_bytes += packet.payload.len();
+                self.out_of_order
+                    .insert(packet.seq_nr, (packet.kind, packet.payload));
+            }
+            return;
+        }
+        if self.read_buf.len() + packet.payload.len() > RECV_WINDOW {
+            return;
+        }
+
+        self.deliver(packet.kind, packet.seq_nr, packet.payload);
+        while let Some((kind, payload)) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+            self.out_of_order_bytes -= payload.len();
+            self.deliver(kind, self.ack_nr.wrapping_add(1), payload);
+        }
+    }
+
+    fn deliver(&mut self, kind: u8, seq_nr: u16, payload: Vec<u8>) {
+        self.ack_nr = seq_nr;
+        if kind == ST_FIN {
+            self.fin_seq = Some(seq_nr);
+        }
+        self.read_buf.extend(payload);
+        if self.fin_seq == Some(self.ack_nr) {
+            self.eof = true;
+        }
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn on_tick(&mut self, now: Instant) {
+        let Some(oldest) = self.in_flight.iter().find(|p| !p.acked) else {
+            return;
+        };
+        if now.duration_since(oldest.sent_at) < self.rto {
+            return;
+        }
+
+        self.timeouts += 1;
+        let max_timeouts = if self.state == State::SynSent {
+            MAX_SYN_TIMEOUTS
+        } else {
+            MAX_TIMEOUTS
+        };
+        if self.timeouts >= max_timeouts {
+            self.fail(io::ErrorKind::TimedOut);
+            return;
+        }
+
+        self.max_window = MIN_WINDOW;
+        if self.state == State::Connected {
+            self.rto = (self.rto * 2).min(MAX_RTO);
+        }
+        self.resend_oldest();
+    }
+
+    fn close(&mut self) {
+        if !self.fin_sent && self.error.is_none() && self.state == State::Connected {
+            self.fin_sent = true;
+            self.send_reliable(ST_FIN, Vec::new());
+        }
+    }
+}
+
+type ConnectionMap = Arc<Mutex<HashMap<(SocketAddr, u16), Arc<Mutex<Connection>>>>>;
+
+// A UDP socket carrying any number of uTP connections, both outgoing and accepted.
+pub struct UtpSocket {
+    socket: Arc<UdpSocket>,
+    connections: ConnectionMap,
+    // None for sockets that only dial out, which refuse incoming connections
+    incoming: Option<tokio::sync::Mutex<mpsc::Receiver<UtpStream>>>,
+    task: JoinHandle<()>,
+}
+
+impl fmt::Debug for UtpSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UtpSocket")
+            .field("local_addr", &self.socket.local_addr().ok())
+            .finish()
+    }
+}
+
+impl Drop for UtpSocket {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl UtpSocket {
+    // Binds a socket that both dials out and accepts connections, which pile up (up to a
+    // backlog) until `accept` takes them.
+    pub async fn bind(addr: impl ToSocketAddrs) -> Result<Self, Error> {
+        Self::bind_with(addr, true).await
+    }
+
+    // Binds a socket that only dials out and resets any connection attempt made to it.
+    pub async fn bind_outgoing(addr: impl ToSocketAddrs) -> Result<Self, Error> {
+        Self::bind_with(addr, false).await
+    }
+
+    async fn bind_with(addr: impl ToSocketAddrs, accepting: bool) -> Result<Self, Error> {
+        let socket = Arc::new(
+            UdpSocket::bind(addr)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to bind uTP socket: {}", e))?,
+        );
+        let connections = ConnectionMap::default();
+        let (sender, receiver) = match accepting {
+            true => {
+                let (sender, receiver) = mpsc::channel(ACCEPT_BACKLOG);
+                (Some(sender), Some(tokio::sync::Mutex::new(receiver)))
+            }
+            false => (None, None),
+        };
+        let task = tokio::spawn(receive_loop(socket.clone(), connections.clone(), sender));
+        Ok(Self {
+            socket,
+            connections,
+            incoming: receiver,
+            task,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub async fn connect(self: &Arc<Self>, remote: SocketAddr) -> Result<UtpStream, Error> {
+        let recv_id: u16 = rng().random();
+        let mut connection = Connection::new(
+            self.socket.clone(),
+            remote,
+            State::SynSent,
+            recv_id.wrapping_add(1),
+        );
+        connection.send_reliable(ST_SYN, Vec::new());
+        let connection = Arc::new(Mutex::new(connection));
+        self.connections
+            .lock()
+            .unwrap()
+            .insert((remote, recv_id), connection.clone());
+        spawn_timer(
+            connection.clone(),
+            self.connections.clone(),
+            (remote, recv_id),
+        );
+
+        std::future::poll_fn(|cx| {
+            let mut connection = connection.lock().unwrap();
+            if let Some(kind) = connection.error {
+                return Poll::Ready(Err(anyhow::anyhow!(
+                    "uTP connection to {} failed: {}",
+                    remote,
+                    io::Error::from(kind)
+                )));
+            }
+            if connection.state == State::Connected {
+                return Poll::Ready(Ok(()));
+            }
+            connection.connect_waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await?;
+
+        Ok(UtpStream {
+            connection,
+            remote,
+            _socket: Some(self.clone()),
+        })
+    }
+
+    pub async fn accept(&self) -> Result<(UtpStream, SocketAddr), Error> {
+        let stream = self
+            .incoming
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("uTP socket doesn't accept connections"))?
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("uTP socket closed"))?;
+        let remote = stream.remote;
+        Ok((stream, remote))
+    }
+}
+
+// Sockets for outgoing uTP connections, one per address family, each bound the first time a
+// peer of that family is dialed. Clones share the sockets.
+#[derive(Debug, Clone, Default)]
+pub struct UtpSockets {
+    v4: Arc<OnceCell<Arc<UtpSocket>>>,
+    v6: Arc<OnceCell<Arc<UtpSocket>>>,
+}
+
+impl UtpSockets {
+    pub async fn for_peer(&self, peer: SocketAddr) -> Result<Arc<UtpSocket>, Error> {
+        let (cell, addr) = match peer {
+            SocketAddr::V4(_) => (&self.v4, "0.0.0.0:0"),
+            SocketAddr::V6(_) => (&self.v6, "[::]:0"),
+        };
+        let socket = cell
+            .get_or_try_init(|| async { UtpSocket::bind_outgoing(addr).await.map(Arc::new) })
+            .await?;
+        Ok(socket.clone())
+    }
+}
+
+async fn receive_loop(
+    socket: Arc<UdpSocket>,
+    connections: ConnectionMap,
+    incoming: Option<mpsc::Sender<UtpStream>>,
+) {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let Ok((len, remote)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let Some(packet) = Packet::from_bytes(&buf[..len]) else {
+            continue;
+        };
+
+        let key = if packet.kind == ST_SYN {
+            (remote, packet.connection_id.wrapping_add(1))
+        } else {
+            (remote, packet.connection_id)
+        };
+        let connection = connections.lock().unwrap().get(&key).cloned();
+        if let Some(connection) = connection {
+            connection.lock().unwrap().on_packet(packet);
+            continue;
+        }
+
+        match packet.kind {
+            ST_SYN => {
+                // turned away without an acceptor, or with a full backlog, rather than left
+                // to hang
+                let Some(permit) = incoming
+                    .as_ref()
+                    .and_then(|incoming| incoming.try_reserve().ok())
+                else {
+                    send_reset(&socket, remote, &packet);
+                    continue;
+                };
+                let mut connection = Connection::new(
+                    socket.clone(),
+                    remote,
+                    State::Connected,
+                    packet.connection_id,
+                );
+                connection.seq_nr = rng().random();
+                connection.ack_nr = packet.seq_nr;
+                connection.reply_micro = now_micros().wrapping_sub(packet.timestamp);
+                connection.send_state();
+                let connection = Arc::new(Mutex::new(connection));
+                let stream = UtpStream {
+                    connection: connection.clone(),
+                    remote,
+                    _socket: None,
+                };
+                permit.send(stream);
+                connections.lock().unwrap().insert(key, connection.clone());
+                spawn_timer(connection, connections.clone(), key);
+            }
+            ST_RESET => {}
+            _ => send_reset(&socket, remote, &packet),
+        }
+    }
+}
+
+// Tells the sender of `packet` that we have no connection for it.
+fn send_reset(socket: &UdpSocket, remote: SocketAddr, packet: &Packet) {
+    let reset = Packet {
+        kind: ST_RESET,
+        connection_id: packet.connection_id,
+        timestamp: now_micros(),
+        timestamp_diff: 0,
+        wnd_size: 0,
+        seq_nr: 0,
+        ack_nr: packet.seq_nr,
+        selective_ack: None,
+        payload: Vec::new(),
+    };
+    let _ = socket.try_send_to(&reset.to_bytes(), remote);
+}
+
+fn spawn_timer(
+    connection: Arc<Mutex<Connection>>,
+    connections: ConnectionMap,
+    key: (SocketAddr, u16),
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TICK);
+        loop {
+            interval.tick().await;
+            let mut conn = connection.lock().unwrap();
+            conn.on_tick(Instant::now());
+            // once the stream is dropped only the timer and the map hold the connection
+            let dropped = Arc::strong_count(&connection) <= 2;
+            if conn.is_finished()
+                || (dropped && (conn.in_flight.is_empty() || conn.error.is_some()))
+            {
+                drop(conn);
+                connections.lock().unwrap().remove(&key);
+                return;
+            }
+        }
+    });
+}
+
+// A single uTP connection, usable anywhere a TCP stream is. Outgoing streams keep their
+// socket alive so its receive loop keeps running.
+pub struct UtpStream {
+    connection: Arc<Mutex<Connection>>,
+    remote: SocketAddr,
+    _socket: Option<Arc<UtpSocket>>,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.remote
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        self.connection.lock().unwrap().close();
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut connection = self.connection.lock().unwrap();
+        if !connection.read_buf.is_empty() {
+            let was_full = connection.receive_window() < PACKET_SIZE;
+            let n = buf.remaining().min(connection.read_buf.len());
+            let (front, back) = connection.read_buf.as_slices();
+            let from_front = n.min(front.len());
+            buf.put_slice(&front[..from_front]);
+            buf.put_slice(&back[..n - from_front]);
+            connection.read_buf.drain(..n);
+            if was_full {
+                // let the peer know the window reopened
+                connection.send_state();
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if connection.eof {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(kind) = connection.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        connection.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut connection = self.connection.lock().unwrap();
+        if let Some(kind) = connection.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if connection.fin_sent {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        let mut written = 0;
+        while written < buf.len() {
+            let len = PACKET_SIZE.min(buf.len() - written);
+            // always allow one packet in flight so a tiny window can't stall the stream
+            if connection.window_available() < len && connection.bytes_in_flight > 0 {
+                break;
+            }
+            connection.send_reliable(ST_DATA, buf[written..written + len].to_vec());
+            written += len;
+        }
+
+        if written == 0 {
+            connection.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.connection.lock().unwrap().close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // A connected connection whose packets go to a socket nobody reads.
+    async fn connection() -> (Connection, UdpSocket) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let remote = sink.local_addr().unwrap();
+        let connection = Connection::new(Arc::new(socket), remote, State::Connected, 1);
+        (connection, sink)
+    }
+
+    fn packet(kind: u8, seq_nr: u16, ack_nr: u16, payload: Vec<u8>) -> Packet {
+        Packet {
+            kind,
+            connection_id: 1,
+            timestamp: 0,
+            timestamp_diff: 0,
+            wnd_size: RECV_WINDOW as u32,
+            seq_nr,
+            ack_nr,
+            selective_ack: None,
+            payload,
+        }
+    }
+
+    #[tokio::test]
+    async fn loopback_connect_transfer_close() {
+        let server = Arc::new(UtpSocket::bind("127.0.0.1:0").await.unwrap());
+        let client = Arc::new(UtpSocket::bind_outgoing("127.0.0.1:0").await.unwrap());
+        let data: Vec<u8> = (0..3 * RECV_WINDOW as u32)
+            .map(|i| (i % 251) as u8)
+            .collect();
+
+        let serving = {
+            let server = server.clone();
+            tokio::spawn(async move {
+                let (mut stream, _) = server.accept().await.unwrap();
+                let mut received = Vec::new();
+                stream.read_to_end(&mut received).await.unwrap();
+                stream.write_all(b"done").await.unwrap();
+                stream.shutdown().await.unwrap();
+                received
+            })
+        };
+
+        let transfer = async {
+            let mut stream = client.connect(server.local_addr().unwrap()).await.unwrap();
+            stream.write_all(&data).await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut reply = Vec::new();
+            stream.read_to_end(&mut reply).await.unwrap();
+            reply
+        };
+        let reply = tokio::time::timeout(Duration::from_secs(30), transfer)
+            .await
+            .unwrap();
+        assert_eq!(reply, b"done");
+        assert!(serving.await.unwrap() == data);
+    }
+
+    #[tokio::test]
+    async fn outgoing_socket_resets_connection_attempts() {
+        let outgoing = Arc::new(UtpSocket::bind_outgoing("127.0.0.1:0").await.unwrap());
+        let client = Arc::new(UtpSocket::bind_outgoing("127.0.0.1:0").await.unwrap());
+        let connecting = client.connect(outgoing.local_addr().unwrap());
+        let result = tokio::time::timeout(Duration::from_secs(5), connecting)
+            .await
+            .unwrap();
+        assert!(result.is_err());
+        assert!(outgoing.connections.lock().unwrap().is_empty());
+        assert!(outgoing.accept().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn sack_counts_each_packet_once() {
+        let (mut connection, _sink) = connection().await;
+        for _ in 0..6 {
+            connection.send_reliable(ST_DATA, vec![0; 100]);
+        }
+        // give the round trip a known length
+        let sent_at = Instant::now() - Duration::from_millis(200);
+        for outgoing in connection.in_flight.iter_mut() {
+            outgoing.sent_at = sent_at;
+        }
+
+        // packet 1 is lost and packets 2 to 4 arrive
+        let mut state = packet(ST_STATE, 1, 0, Vec::new());
+        state.selective_ack = Some(vec![0b111, 0, 0, 0]);
+        connection.on_ack(&state);
+        assert_eq!(connection.in_flight[0].transmissions, 2);
+
+        // the same acks again don't count the packets again, nor resend within the round trip
+        for _ in 0..5 {
+            connection.on_ack(&state);
+        }
+        assert_eq!(connection.sacked_past_hole, 0);
+        assert_eq!(connection.in_flight[0].transmissions, 2);
+
+        // a round trip later, duplicate acks resend again
+        connection.last_fast_resend = Some(Instant::now() - Duration::from_secs(1));
+        connection.on_ack(&state);
+        assert_eq!(connection.in_flight[0].transmissions, 3);
+    }
+
+    #[tokio::test]
+    async fn fast_resend_waits_for_timeout_before_first_rtt_sample() {
+        let (mut connection, _sink) = connection().await;
+        for _ in 0..8 {
+            connection.send_reliable(ST_DATA, vec![0; 100]);
+        }
+        // resent packets give no round trip samples
+        for outgoing in connection.in_flight.iter_mut() {
+            outgoing.transmissions = 2;
+        }
+        for mask in [0b111u8, 0b1111, 0b11111, 0b111111] {
+            let mut state = packet(ST_STATE, 1, 0, Vec::new());
+            state.selective_ack = Some(vec![mask, 0, 0, 0]);
+            connection.on_ack(&state);
+        }
+        assert_eq!(connection.rtt_us, 0.0);
+        assert_eq!(connection.in_flight[0].transmissions, 3);
+    }
+
+    #[tokio::test]
+    async fn data_past_receive_window_is_refused() {
+        let (mut connection, _sink) = connection().await;
+        let fits = (RECV_WINDOW / PACKET_SIZE) as u16;
+        for seq_nr in 1..=fits + 10 {
+            connection.on_data(packet(ST_DATA, seq_nr, 0, vec![0; PACKET_SIZE]));
+        }
+        assert_eq!(connection.ack_nr, fits);
+        assert_eq!(connection.read_buf.len(), fits as usize * PACKET_SIZE);
+        assert!(connection.receive_window() < PACKET_SIZE);
+
+        // once the reader makes room, the resent packet is taken
+        connection.read_buf.clear();
+        connection.on_data(packet(ST_DATA, fits + 1, 0, vec![0; PACKET_SIZE]));
+        assert_eq!(connection.ack_nr, fits + 1);
+    }
+
+    #[tokio::test]
+    async fn out_of_order_data_is_bounded_by_receive_window() {
+        let (mut connection, _sink) = connection().await;
+        for seq_nr in 2..REORDER_LIMIT {
+            connection.on_data(packet(ST_DATA, seq_nr, 0, vec![0; 4 * PACKET_SIZE]));
+        }
+        assert!(connection.out_of_order_bytes <= RECV_WINDOW);
+        assert_eq!(connection.receive_window(), RECV_WINDOW % (4 * PACKET_SIZE));
+
+        // a full window of out-of-order data doesn't keep out the packet filling the hole
+        let buffered = connection.out_of_order.len() as u16;
+        connection.on_data(packet(ST_DATA, 1, 0, vec![0; PACKET_SIZE]));
+        assert_eq!(connection.ack_nr, 1 + buffered);
+        assert_eq!(connection.out_of_order_bytes, 0);
+    }
+}